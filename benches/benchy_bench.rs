@@ -3,13 +3,13 @@ use hm::{
   config::{deserialize_file, Config, ManagedObject},
   get_task_batches,
 };
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 fn criterion_benchmark(c: &mut Criterion) {
   let a: Config = deserialize_file("./benches/config.toml").unwrap();
   let nodes: HashMap<String, ManagedObject> = Config::as_managed_objects(a);
   c.bench_function("get_task_batches", |b| {
-    b.iter(|| get_task_batches(nodes.clone(), None))
+    b.iter(|| get_task_batches(nodes.clone(), HashSet::new()))
   });
 }
 
@@ -18,6 +18,7 @@
 //!
 //!  1. create a config.toml file either anywhere or in ~/.config/homemaker/.
 //!  2. enter things to do things to in the file.
+//!
 //!  example:
 //!  ``` text
 //!  ## config.toml
@@ -47,66 +48,279 @@
 //!  serde
 //!  toml
 //!  symlink
-//!  solvent
 //!  indicatif
 //!  console
 
 use ::hm::{
-  config::{deserialize_file, ensure_config_dir, Config},
-  do_tasks, hmerror,
+  applies_to,
+  condition::Condition,
+  config::{
+    deserialize_file, ensure_config_dir, exclude, select_by_tags, Config, ManagedObject, OsFilter,
+    Platform,
+  },
+  depgraph::DepGraphError,
+  do_tasks,
+  facts::Facts,
+  get_task_batches,
+  graph::{depgraph_of, Graph},
+  hmerror::{self, ErrorKind, HMError},
+  run_tasks,
+  state::{self, State},
+  RunOptions,
 };
 use chrono::prelude::*;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use console::style;
 use indicatif::HumanDuration;
 use log::{info, warn};
+use serde::Serialize;
+use serde_json::{json, Value};
 use simplelog::{ConfigBuilder, LevelFilter, WriteLogger};
-use std::{env, fs::File, path::PathBuf, process::exit, string::String, time::Instant};
+use std::{
+  collections::{HashMap, HashSet},
+  env,
+  fs::File,
+  path::PathBuf,
+  process::exit,
+  str::FromStr,
+  string::String,
+  time::Instant,
+};
+
+/// homemaker. Slightly more than yet another dotfile manager.
+#[derive(Parser, Debug)]
+#[command(name = "hm", version, about)]
+struct Cli {
+  /// Config file to use. Defaults to ~/.config/homemaker/config.toml
+  #[arg(short, long, global = true, value_name = "CONFIG")]
+  config: Option<String>,
+  /// Act as if we're on this platform (`linux::arch`, `linux::ubuntu::22.04`, `windows`). Same as setting HM_OS
+  #[arg(long, global = true, value_name = "PLATFORM", value_parser = parse_os)]
+  os: Option<String>,
+  /// `json` for scripts: apply, status, list, graph, validate and facts print JSON instead
+  #[arg(long, global = true, value_enum, default_value_t = Output::Text)]
+  output: Output,
+  #[command(subcommand)]
+  command: Option<Cmd>,
+  /// With no subcommand, we `apply`.
+  #[command(flatten)]
+  apply: ApplyArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Cmd {
+  /// Put files in place and run tasks, in dependency order (what plain `hm` does)
+  Apply(ApplyArgs),
+  /// How each object fared last run, and when each task last succeeded
+  Status,
+  /// Every file and task in the config
+  List {
+    /// Just the names, one per line
+    #[arg(long)]
+    names: bool,
+  },
+  /// What depends on what - as text, Graphviz DOT, or a Mermaid flowchart
+  Graph {
+    #[arg(long, value_enum, default_value_t = GraphFormat::Text)]
+    format: GraphFormat,
+    /// Only what these tasks need. Repeatable, or comma-separated
+    #[arg(short, long = "task", value_name = "TASK", value_delimiter = ',')]
+    task: Vec<String>,
+  },
+  /// Everything that depends on an object, directly or not - what would break without it
+  Why {
+    #[arg(value_name = "TASK")]
+    name: String,
+  },
+  /// Everything an object needs, all the way down, in the order it'd be done
+  Deps {
+    #[arg(value_name = "TASK")]
+    name: String,
+  },
+  /// Print the path of the most recent log file (use with your editor - `nvr (hm log)`)
+  #[command(alias = "logs")]
+  Log,
+  /// Remove the log directory
+  Clean,
+  /// Check the config: every dependency defined, no cycles, every `when` makes sense
+  Validate,
+  /// Print what hm knows about this machine (os, distro, arch, hostname...)
+  Facts {
+    /// As JSON, for scripts
+    #[arg(long)]
+    json: bool,
+  },
+  /// Print a completion script for your shell - `hm completions bash > ~/.local/share/bash-completion/completions/hm`
+  Completions {
+    #[arg(value_enum)]
+    shell: Shell,
+  },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Output {
+  Text,
+  Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum GraphFormat {
+  Text,
+  Dot,
+  Mermaid,
+}
 
-/// Pull apart our arguments, if they're called, get our Config, and error-check.
-/// Then work our way through the Config, executing the easy stuff, and threading off the hard.
+#[derive(Args, Debug, Default, PartialEq, Eq)]
+struct ApplyArgs {
+  /// Only run these tasks (and what they depend on). Repeatable, or comma-separated
+  #[arg(short, long = "task", value_name = "TASK", value_delimiter = ',')]
+  task: Vec<String>,
+  /// Leave out these tasks, and whatever depends on them. Repeatable, or comma-separated
+  #[arg(long, value_name = "TASK", value_delimiter = ',')]
+  exclude: Vec<String>,
+  /// Only objects with one of these tags (and what they depend on)
+  #[arg(long, value_name = "TAG", value_delimiter = ',')]
+  tag: Vec<String>,
+  /// Leave out objects with any of these tags
+  #[arg(long, value_name = "TAG", value_delimiter = ',')]
+  skip_tag: Vec<String>,
+  /// The tags and skip_tags of [profiles.<name>] in the config
+  #[arg(long, value_name = "NAME")]
+  profile: Option<String>,
+  /// Run every task, even ones unchanged since they last succeeded
+  #[arg(short, long)]
+  all: bool,
+  /// Only run what failed, was skipped, or never started last run
+  #[arg(short, long)]
+  resume: bool,
+  /// Print what would be done, in order, without doing it
+  #[arg(short = 'n', long)]
+  dry_run: bool,
+}
+
+fn parse_os(s: &str) -> Result<String, String> {
+  OsFilter::from_str(s).map(|_| s.to_string()).map_err(|_e| {
+    String::from("expected something like `linux::arch`, `linux::ubuntu::22.04` or `windows`")
+  })
+}
+
+/// Our arguments, as long as they make sense together.
+fn parse<I, T>(args: I) -> Result<Cli, clap::Error>
+where
+  I: IntoIterator<Item = T>,
+  T: Into<std::ffi::OsString> + Clone,
+{
+  let cli = Cli::try_parse_from(args)?;
+  if cli.command.is_none() || cli.apply == ApplyArgs::default() {
+    return Ok(cli);
+  }
+  let complaint = match cli.command {
+    // `hm -n apply` would otherwise quietly drop the -n
+    Some(Cmd::Apply(_)) => "apply's options (-t, --exclude, --tag...) go after `apply`",
+    // `-c` and `--os` go anywhere, but `hm -t zt status` is almost certainly a mistake
+    _ => "apply's options (-t, --exclude, --tag...) can't be used with another subcommand",
+  };
+  Err(Cli::command().error(clap::error::ErrorKind::ArgumentConflict, complaint))
+}
+
+/// Pull apart our arguments, get our Config, and error-check.
+/// Then hand off to whichever subcommand we were asked for - `apply`, unless told otherwise.
 fn main() {
-  let args: Vec<String> = env::args().collect();
-  // it's a little hackish, but we don't have to bring in an external crate to do our args
-  let mut target_task: Option<String> = None;
-  let mut arg_config: Option<String> = None;
-  for i in 0..args.len() {
-    match args[i].as_str() {
-      "log" | "logs" => {
-        println!("{}", recent_log_path());
-        exit(0)
-      }
-      "-t" | "--task" => {
-        if args.len() > i && !args[i + 1].starts_with('-') {
-          // ensure the next arg is not a flag
-          // assume the next one is the named task to complete
-          target_task = Some(args[i + 1].clone());
-        } else {
-          hmerror::error(
-            "-t flag requires specified task immediately after",
-            "No task was specified.",
-          );
-          help();
-        }
-      }
-      "clean" | "--clean" => {
-        match clean() {
-          Ok(_) => {
-            exit(0);
-          }
-          Err(e) => {
-            eprintln!("{}", e);
-            exit(0);
-          }
-        };
-      }
-      "-c" | "--config" if args.len() > i + 1 => {
-        arg_config = Some(args[i + 1].clone());
-      }
-      "-h" | "--help" => {
-        help();
+  let cli = parse(env::args_os()).unwrap_or_else(|e| e.exit());
+  if let Some(o) = &cli.os {
+    // everything that works out our platform looks at this
+    env::set_var("HM_OS", o);
+  }
+  // --os is checked as it's parsed, but HM_OS from the environment isn't
+  if let Some(o) = env::var("HM_OS").ok().filter(|o| !o.is_empty()) {
+    if let Err(e) = parse_os(&o) {
+      fatal(
+        cli.output,
+        format!("HM_OS `{}` isn't a platform we know", o).as_str(),
+        &HMError::Other(e),
+      )
+    }
+  }
+  match cli.command {
+    Some(Cmd::Log) => {
+      println!("{}", recent_log_path());
+      exit(0)
+    }
+    Some(Cmd::Clean) => {
+      if let Err(e) = clean() {
+        eprintln!("{}", e);
       }
-      _ => {}
+      exit(0)
     }
+    Some(Cmd::Facts { json }) => facts(json || cli.output == Output::Json),
+    Some(Cmd::Status) => status(load_config(cli.config, cli.output), cli.output),
+    Some(Cmd::List { names }) => list(load_config(cli.config, cli.output), names, cli.output),
+    Some(Cmd::Completions { shell }) => completions(shell),
+    Some(Cmd::Graph { format, task }) => graph(
+      load_config(cli.config, cli.output),
+      format,
+      task,
+      cli.output,
+    ),
+    Some(Cmd::Validate) => validate(
+      deserialize_file(&config_path(cli.config, cli.output)),
+      cli.output,
+    ),
+    Some(Cmd::Why { name }) => why(load_config(cli.config, Output::Text), name),
+    Some(Cmd::Deps { name }) => deps(load_config(cli.config, Output::Text), name),
+    Some(Cmd::Apply(a)) => apply(a, cli.config, cli.output),
+    None => apply(cli.apply, cli.config, cli.output),
+  }
+}
+
+/// The config passed in specifically (with -c or --config), or the default config location.
+fn config_path(arg_config: Option<String>, output: Output) -> String {
+  match arg_config {
+    Some(c) => c,
+    None => match ensure_config_dir() {
+      Ok(p) => p.to_string_lossy().to_string(),
+      Err(e) => fatal(
+        output,
+        "Couldn't ensure config dir",
+        &HMError::Other(e.to_string()),
+      ),
+    },
+  }
+}
+
+/// Accept either a config passed in specifically (with -c or --config) or try to open the default config location.
+fn load_config(arg_config: Option<String>, output: Output) -> Config {
+  let specified = arg_config.is_some();
+  let path = config_path(arg_config, output);
+  match deserialize_file(&path) {
+    Ok(c) => c,
+    Err(e) if specified => fatal(
+      output,
+      format!("Couldn't open specified config file `{}`", path).as_str(),
+      &e,
+    ),
+    Err(e) => fatal(
+      output,
+      format!("Couldn't open assumed (unspecified) config file {}", path).as_str(),
+      &e,
+    ),
+  }
+}
+
+/// We can't get started: say why - as JSON on stdout, if that's what was asked for - and exit(1).
+fn fatal(output: Output, what: &str, e: &HMError) -> ! {
+  if output == Output::Json {
+    let mut error = error_json(e);
+    error["message"] = json!(format!("{}: {}", what, e));
+    print_json(&json!({ "ok": false, "exit_code": 1, "error": error }), 1)
   }
+  hmerror::error(what, e.to_string().as_str());
+  exit(1)
+}
+
+/// Work our way through the Config, executing the easy stuff, and threading off the hard.
+fn apply(args: ApplyArgs, arg_config: Option<String>, output: Output) -> ! {
   let l = Local::now();
   let mut slc = ConfigBuilder::new();
   let _ = slc.set_time_offset_to_local();
@@ -124,59 +338,501 @@ fn main() {
   p.push_str(log_file_name.as_str());
   // nifty thing is we can make it here, and then we _never_
   // have to pass it around - singleton. just info!, trace!, warn!, etc
-  let _ = WriteLogger::init(LevelFilter::Trace, slc.build(), File::create(p).unwrap());
+  let _ = WriteLogger::init(
+    LevelFilter::Trace,
+    slc.build(),
+    File::create(p.as_str()).unwrap(),
+  );
   info!("beginning hm execution...");
-  /*
-  accept either a config passed in specifically (with -c or --config) or try to open the default config location
-   */
-  let a: Config = match arg_config {
-    Some(second) => match deserialize_file(&second) {
-      Ok(c) => c,
-      Err(e) => {
+  let a: Config = load_config(arg_config, output);
+  let mut tags = args.tag;
+  let mut skip_tags = args.skip_tag;
+  if let Some(n) = args.profile {
+    match a.profiles.get(&n) {
+      Some(pr) => {
+        tags.extend(pr.tags.iter().cloned());
+        skip_tags.extend(pr.skip_tags.iter().cloned());
+      }
+      None => {
+        let complaint = format!("No profile named `{}`", n);
+        if output == Output::Json {
+          print_json(
+            &json!({ "ok": false, "exit_code": 1, "error": error_json(&HMError::Other(complaint)) }),
+            1,
+          )
+        }
         hmerror::error(
-          format!("Couldn't open specified config file `{}`", &second).as_str(),
-          e.to_string().as_str(),
+          complaint.as_str(),
+          "Profiles are defined in the config like `[profiles.server]`.",
         );
         exit(1)
       }
-    },
-    None => {
-      let _p: PathBuf = ensure_config_dir()
-        .map_err(|e| {
-          hmerror::error("Couldn't ensure config dir: {}", e);
-          exit(1);
-        })
-        .unwrap();
-      match deserialize_file(_p.to_str().unwrap()) {
-        Ok(c) => c,
-        Err(e) => {
-          hmerror::error(
-            format!(
-              "Couldn't open assumed (unspecified) config file {}",
-              _p.to_string_lossy()
-            )
-            .as_str(),
-            e.to_string().as_str(),
-          );
-          exit(1)
-        }
-      }
     }
+  }
+  for e in args
+    .exclude
+    .iter()
+    .filter(|e| !a.files.iter().any(|(n, _)| n == *e))
+  {
+    // most likely a typo, and then whatever it was meant to leave out is going to run
+    warn!("Nothing called {} to exclude.", e);
+    eprintln!(
+      "{} nothing in the config is called `{}`, so there's nothing to exclude",
+      style("-").yellow(),
+      e
+    );
+  }
+  let targets: HashSet<String> = args.task.into_iter().collect();
+  if args.dry_run {
+    show_plan(
+      exclude(
+        select_by_tags(Config::as_managed_objects(a), &tags, &skip_tags),
+        &args.exclude,
+      ),
+      targets,
+      output,
+    );
+  }
+  let opts = RunOptions {
+    state_file: state::state_file()
+      .map_err(|e| warn!("Couldn't find somewhere to keep state: {}", e))
+      .ok(),
+    ignore_state: args.all,
+    resume: args.resume,
+    log_file: Some(PathBuf::from(p)),
+    tags,
+    skip_tags,
+    exclude: args.exclude,
+    quiet: output == Output::Json,
+    ..Default::default()
   };
   // do it here
   let started = Instant::now();
-  match do_tasks(Config::as_managed_objects(a), target_task) {
+  if output == Output::Json {
+    let (code, objects, error) = match run_tasks(Config::as_managed_objects(a), targets, opts) {
+      Ok(r) if r.failed().is_empty() => (0, r.objects, Value::Null),
+      Ok(r) => (3, r.objects, Value::Null),
+      Err(e) => (3, Vec::new(), error_json(&e)),
+    };
+    print_json(
+      &json!({
+        "ok": code == 0,
+        "exit_code": code,
+        "duration_ms": started.elapsed().as_millis() as u64,
+        "objects": objects,
+        "error": error,
+      }),
+      code,
+    )
+  }
+  match do_tasks(Config::as_managed_objects(a), targets, opts) {
     Ok(_) => {
       println!("Done in {}.", HumanDuration(started.elapsed()));
       exit(0);
     }
     Err(e) => {
-      hmerror::error(format!("{}", e).as_str(), "poooooop");
+      // the failures themselves have already been printed, each with its output
+      hmerror::error("Not everything got done", e.to_string().as_str());
       exit(3);
     }
   }
 }
 
+/// Every object in the config, in name order.
+fn sorted_objects(mos: &HashMap<String, ManagedObject>) -> Vec<&ManagedObject> {
+  let mut v: Vec<&ManagedObject> = mos.values().collect();
+  v.sort_by(|x, y| x.name.cmp(&y.name));
+  v
+}
+
+fn kind(mo: &ManagedObject) -> &'static str {
+  if mo.is_task() {
+    "task"
+  } else {
+    "file"
+  }
+}
+
+/// How each object fared last run, and when each task last succeeded.
+fn status(a: Config, output: Output) -> ! {
+  let state: State = match state::state_file() {
+    Ok(f) => State::load(&f),
+    Err(e) => fatal(
+      output,
+      "Couldn't find our state file",
+      &HMError::Other(e.to_string()),
+    ),
+  };
+  let mos = Config::as_managed_objects(a);
+  if output == Output::Json {
+    let objects: Vec<Value> = sorted_objects(&mos)
+      .iter()
+      .map(|mo| {
+        let record = state.tasks.get(&mo.name);
+        json!({
+          "name": mo.name,
+          "kind": kind(mo),
+          "last_run": state.last_run.get(&mo.name),
+          "last_success": record.map(|r| &r.completed),
+          "changed_since": record.is_some() && !state.is_unchanged(mo),
+        })
+      })
+      .collect();
+    print_json(&objects, 0)
+  }
+  for mo in sorted_objects(&mos) {
+    let last_run = match state.last_run.get(&mo.name) {
+      Some(o) => format!("{:?}", o).to_lowercase(),
+      None => String::from("-"),
+    };
+    let last_success = match state.tasks.get(&mo.name) {
+      Some(r) if state.is_unchanged(mo) => format!("succeeded {}", r.completed),
+      Some(r) => format!("succeeded {}, changed since", r.completed),
+      None => String::new(),
+    };
+    println!(
+      "{} {:<30} {:<12} {}",
+      kind(mo),
+      mo.name,
+      last_run,
+      last_success
+    );
+  }
+  exit(0)
+}
+
+/// Every file and task in the config - what we'd do with it, and whether it's for this machine.
+fn list(a: Config, names: bool, output: Output) -> ! {
+  let mos = Config::as_managed_objects(a);
+  let mut sorted = sorted_objects(&mos);
+  if names {
+    for mo in sorted {
+      println!("{}", mo.name);
+    }
+    exit(0)
+  }
+  let platform = Platform::detect();
+  if output == Output::Json {
+    let objects: Vec<Value> = sorted
+      .iter()
+      .map(|mo| {
+        let applies = applies_to(mo, &platform);
+        let mut v = serde_json::to_value(mo).unwrap_or_default();
+        v["kind"] = json!(kind(mo));
+        v["applies"] = json!(applies.is_ok());
+        v["reason"] = json!(applies.err());
+        v
+      })
+      .collect();
+    print_json(&objects, 0)
+  }
+  sorted.sort_by_key(|m| m.is_task());
+  for (i, mo) in sorted.iter().enumerate() {
+    if i == 0 || sorted[i - 1].is_task() != mo.is_task() {
+      println!(
+        "{}",
+        style(if mo.is_task() { "tasks" } else { "files" }).bold()
+      );
+    }
+    match applies_to(mo, &platform) {
+      Ok(()) => println!("  {} {}", style("✓").green(), mo.name),
+      Err(e) => println!(
+        "  {} {} {}",
+        style("-").yellow(),
+        mo.name,
+        style(format!("(not here: {})", why_not(&e))).dim()
+      ),
+    }
+    for d in mo.details() {
+      println!("      {}", style(d).dim());
+    }
+  }
+  exit(0)
+}
+
+/// Why `applies_to()` turned an object down, in a few words.
+fn why_not(e: &ErrorKind) -> String {
+  match e {
+    ErrorKind::IncorrectPlatformError { platform, .. } => format!("we're {}", platform),
+    ErrorKind::ExcludedPlatformError {
+      excluded_platform, ..
+    } => format!("not for {}", excluded_platform),
+    ErrorKind::IncorrectArchError { arch, .. } => format!("we're {}", arch),
+    ErrorKind::IncorrectHostError { hostname, .. } => format!("we're {}", hostname),
+    ErrorKind::ConditionNotMetError { .. } => String::from("`when` doesn't hold"),
+    ErrorKind::InvalidConditionError { reason, .. } => format!("bad `when`: {}", reason),
+    o => format!("{:?}", o),
+  }
+}
+
+/// Print a completion script for `shell`. On top of what clap gives us, `-t`, `--exclude`,
+/// `why` and `deps` complete the names of the objects in whichever config is in play, courtesy of `hm list --names`.
+fn completions(shell: Shell) -> ! {
+  let mut script: Vec<u8> = Vec::new();
+  clap_complete::generate(shell, &mut Cli::command(), "hm", &mut script);
+  let script = String::from_utf8_lossy(&script);
+  match shell {
+    Shell::Bash => print!("{}{}", script, BASH_OBJECTS),
+    // `_hm_objects` has to exist before the first completion, which can happen as the file's loaded
+    Shell::Zsh => print!(
+      "{}",
+      script
+        .replacen('\n', &format!("\n{}", ZSH_OBJECTS), 1)
+        .replace(":TASK:_default", ":TASK:_hm_objects")
+        // `why` and `deps` take the one name
+        .replace("':name:_default'", "':name:_hm_objects'")
+    ),
+    Shell::Fish => print!("{}{}", script, FISH_OBJECTS),
+    _ => print!("{}", script),
+  }
+  exit(0)
+}
+
+const BASH_OBJECTS: &str = r#"
+_hm_objects() {
+    local cur="${COMP_WORDS[COMP_CWORD]}" prev="${COMP_WORDS[COMP_CWORD-1]}" cfg=() i
+    case "${prev}" in
+        -t|--task|--exclude|why|deps)
+            for ((i = 1; i < COMP_CWORD - 1; i++)); do
+                case "${COMP_WORDS[i]}" in
+                    -c|--config) cfg=(-c "${COMP_WORDS[i+1]}") ;;
+                esac
+            done
+            COMPREPLY=($(compgen -W "$(hm list --names "${cfg[@]}" 2>/dev/null)" -- "${cur}"))
+            return 0
+            ;;
+    esac
+    _hm "$@"
+}
+complete -F _hm_objects -o nosort -o bashdefault -o default hm
+"#;
+
+const ZSH_OBJECTS: &str = r#"_hm_objects() {
+    local -a names cfg
+    [[ -n ${opt_args[-c]:-${opt_args[--config]}} ]] && cfg=(-c ${opt_args[-c]:-${opt_args[--config]}})
+    names=(${(f)"$(hm list --names $cfg 2>/dev/null)"})
+    _describe 'object' names
+}
+"#;
+
+const FISH_OBJECTS: &str = r#"
+function __hm_objects
+    set -l words (commandline -opc)
+    set -l cfg
+    for i in (seq (math (count $words) - 1))
+        if contains -- $words[$i] -c --config
+            set cfg -c $words[(math $i + 1)]
+        end
+    end
+    hm list --names $cfg 2>/dev/null
+end
+complete -c hm -s t -l task -f -a '(__hm_objects)'
+complete -c hm -l exclude -f -a '(__hm_objects)'
+complete -c hm -n '__fish_seen_subcommand_from why deps' -f -a '(__hm_objects)'
+"#;
+
+/// What depends on what.
+fn graph(a: Config, format: GraphFormat, targets: Vec<String>, output: Output) -> ! {
+  let g = match Graph::new(
+    &Config::as_managed_objects(a),
+    &targets.into_iter().collect(),
+    &Platform::detect(),
+  ) {
+    Ok(g) => g,
+    Err(e) => fatal(output, "Couldn't draw the graph", &e),
+  };
+  if output == Output::Json {
+    print_json(&g.to_json(), 0)
+  }
+  match format {
+    GraphFormat::Text => print!("{}", g.to_text()),
+    GraphFormat::Dot => print!("{}", g.to_dot()),
+    GraphFormat::Mermaid => print!("{}", g.to_mermaid()),
+  }
+  exit(0)
+}
+
+/// What to say when `why` or `deps` can't get an answer.
+fn unknown(e: DepGraphError) -> String {
+  match e {
+    DepGraphError::NoSuchNode(n) => format!("nothing in the config is called `{}`, or needs it", n),
+    DepGraphError::Cycle(c) => format!("it's caught in a cycle: {}", c.join(" -> ")),
+  }
+}
+
+/// Who needs `name`, and through what - check before taking something out of the config.
+fn why(a: Config, name: String) -> ! {
+  let mos = Config::as_managed_objects(a);
+  let dependents = match depgraph_of(&mos).dependents_of(&name) {
+    Ok(d) => d,
+    Err(e) => {
+      hmerror::error(&format!("Can't say why {}", name), &unknown(e));
+      exit(1)
+    }
+  };
+  if dependents.is_empty() {
+    println!("nothing depends on {}", name);
+  }
+  for d in &dependents {
+    let mo = &mos[d];
+    if mo.dependencies.contains(&name) {
+      println!("{} {}", d, style("(directly)").dim());
+    } else {
+      // whichever of its dependencies gets it there
+      let via = mo
+        .dependencies
+        .iter()
+        .find(|x| dependents.contains(x))
+        .cloned()
+        .unwrap_or_default();
+      println!("{} {}", d, style(format!("(through {})", via)).dim());
+    }
+  }
+  let after: Vec<&str> = sorted_objects(&mos)
+    .iter()
+    .filter(|m| m.after.contains(&name))
+    .map(|m| m.name.as_str())
+    .collect();
+  if !after.is_empty() {
+    println!(
+      "{}",
+      style(format!(
+        "goes after it, but doesn't need it: {}",
+        after.join(", ")
+      ))
+      .dim()
+    );
+  }
+  exit(0)
+}
+
+/// What `name` needs, in the order it'd be done - and anything that'd stop that here.
+fn deps(a: Config, name: String) -> ! {
+  let mos = Config::as_managed_objects(a);
+  let platform = Platform::detect();
+  let mut needs = match depgraph_of(&mos).dependencies_of(&name) {
+    Ok(d) => d,
+    Err(e) => {
+      hmerror::error(&format!("Can't work out what {} needs", name), &unknown(e));
+      exit(1)
+    }
+  };
+  if !mos.contains_key(&name) {
+    hmerror::error(
+      &format!("Can't work out what {} needs", name),
+      &format!(
+        "`{}` is only ever a dependency - nothing in the config defines it",
+        name
+      ),
+    );
+    exit(1)
+  }
+  // last is always `name` itself
+  needs.pop();
+  if needs.is_empty() {
+    println!("{} doesn't depend on anything", name);
+  }
+  for n in &needs {
+    match mos.get(n).map(|m| applies_to(m, &platform)) {
+      Some(Ok(())) => println!("{}", n),
+      Some(Err(e)) => println!(
+        "{} {}",
+        n,
+        style(format!("(not here: {})", why_not(&e))).dim()
+      ),
+      None => println!("{} {}", n, style("(undefined)").red()),
+    }
+  }
+  exit(0)
+}
+
+/// Check the config over without doing anything: every dependency is defined somewhere,
+/// every `when` parses, and what applies here can be put in order.
+fn validate(a: hmerror::Result<Config>, output: Output) -> ! {
+  // which object (if we can say), what's wrong, and how to say it to a person
+  let mut problems: Vec<(Option<String>, ErrorKind, String)> = Vec::new();
+  let a = match a {
+    Ok(a) => a,
+    Err(e) => {
+      // nothing more to check if we can't read it
+      let kind = match &e {
+        HMError::Regular(k) => k.clone(),
+        _ => ErrorKind::Other,
+      };
+      problems.push((None, kind, format!("the config doesn't load: {}", e)));
+      report_problems(0, problems, output)
+    }
+  };
+  let mos = Config::as_managed_objects(a);
+  for mo in sorted_objects(&mos) {
+    for d in &mo.dependencies {
+      if !mos.contains_key(d) {
+        problems.push((
+          Some(mo.name.clone()),
+          ErrorKind::DependencyUndefinedError {
+            dependency: d.clone(),
+          },
+          format!("{} depends on `{}`, which isn't defined", mo.name, d),
+        ));
+      }
+    }
+    if !mo.when.is_empty() {
+      if let Err(e) = Condition::parse(&mo.when) {
+        problems.push((
+          Some(mo.name.clone()),
+          ErrorKind::InvalidConditionError {
+            dependency: mo.name.clone(),
+            condition: mo.when.clone(),
+            reason: e.clone(),
+          },
+          format!("{}'s `when` doesn't parse: {}", mo.name, e),
+        ));
+      }
+    }
+  }
+  if problems.is_empty() {
+    if let Err(e) = get_task_batches(mos.clone(), HashSet::new()) {
+      let kind = match &e {
+        HMError::Regular(k) => k.clone(),
+        _ => ErrorKind::Other,
+      };
+      problems.push((None, kind, e.to_string()));
+    }
+  }
+  report_problems(mos.len(), problems, output)
+}
+
+/// How `validate` went: all good, or each of `problems`.
+fn report_problems(
+  objects: usize,
+  problems: Vec<(Option<String>, ErrorKind, String)>,
+  output: Output,
+) -> ! {
+  let code = if problems.is_empty() { 0 } else { 1 };
+  if output == Output::Json {
+    print_json(
+      &json!({
+        "ok": code == 0,
+        "exit_code": code,
+        "objects": objects,
+        "problems": problems
+          .iter()
+          .map(|(o, k, m)| json!({ "object": o, "error": k, "message": m }))
+          .collect::<Vec<Value>>(),
+      }),
+      code,
+    )
+  }
+  if problems.is_empty() {
+    hmerror::happy_print(format!("{} objects, all good", objects).as_str());
+    exit(0)
+  }
+  for (_o, _k, p) in &problems {
+    eprintln!("{} {}", style("✗").red(), p);
+  }
+  exit(code)
+}
+
 fn recent_log_path() -> String {
   let contents: Vec<PathBuf> = std::fs::read_dir("./logs/")
     .map(|res| res.map(|e| e.expect("AIYEEEE").path()))
@@ -192,7 +848,17 @@ fn recent_log_path() -> String {
 
   let mut files: std::collections::BTreeMap<std::time::SystemTime, &PathBuf> =
     std::collections::BTreeMap::new();
-  for item in &contents {
+  // each task's log sits next to its run's (`<run>.log` and `<run>.<task>.log`) - we want the run's
+  let stems: Vec<String> = contents
+    .iter()
+    .filter_map(|p| p.file_stem().map(|s| format!("{}.", s.to_string_lossy())))
+    .collect();
+  for item in contents.iter().filter(|p| {
+    let n = p.file_name().unwrap().to_string_lossy();
+    !stems
+      .iter()
+      .any(|s| n.starts_with(s.as_str()) && n != format!("{}log", s))
+  }) {
     let m = item.metadata();
     files.insert(m.unwrap().accessed().unwrap(), item);
   }
@@ -206,23 +872,116 @@ fn recent_log_path() -> String {
     .to_string()
 }
 
+/// Print what we know about this machine, as text or JSON.
+fn facts(json: bool) -> ! {
+  let f = Facts::detect();
+  if json {
+    print_json(&f, 0)
+  } else {
+    print!("{}", f);
+  }
+  exit(0)
+}
+
+/// Show what we'd do, in the order we'd do it, without doing any of it.
+fn show_plan(mos: HashMap<String, ManagedObject>, targets: HashSet<String>, output: Output) -> ! {
+  let platform = Platform::detect().os;
+  let batches = match get_task_batches(mos, targets) {
+    Ok(b) => b,
+    Err(e) if output == Output::Json => print_json(
+      &json!({ "ok": false, "exit_code": 3, "platform": platform, "plan": [], "error": error_json(&e) }),
+      3,
+    ),
+    Err(e) => {
+      hmerror::error("Couldn't work out what to do", e.to_string().as_str());
+      exit(3)
+    }
+  };
+  if output == Output::Json {
+    let plan: Vec<Value> = batches
+      .iter()
+      .enumerate()
+      .flat_map(|(layer, b)| {
+        b.iter()
+          .map(move |m| json!({ "name": m.name, "kind": kind(m), "layer": layer }))
+      })
+      .collect();
+    print_json(
+      &json!({ "ok": true, "exit_code": 0, "platform": platform, "plan": plan, "error": null }),
+      0,
+    )
+  }
+  println!("platform: {}", platform);
+  for (i, m) in batches.iter().flatten().enumerate() {
+    if m.is_task() {
+      println!("  {}. task {}", i + 1, m.name);
+    } else {
+      println!(
+        "  {}. file {} ({} {} -> {})",
+        i + 1,
+        m.name,
+        m.method,
+        m.source,
+        m.destination
+      );
+    }
+  }
+  exit(0)
+}
+
+/// Print `v` as JSON, and exit with `code`.
+fn print_json<T: Serialize + ?Sized>(v: &T, code: i32) -> ! {
+  match serde_json::to_string_pretty(v) {
+    Ok(s) => println!("{}", s),
+    Err(e) => {
+      hmerror::error("Couldn't serialize output", e.to_string().as_str());
+      exit(1)
+    }
+  }
+  exit(code)
+}
+
+/// An error as JSON: its `ErrorKind`, if it has one, plus a message for people.
+fn error_json(e: &HMError) -> Value {
+  let mut v = match e {
+    HMError::Regular(k) => serde_json::to_value(k).unwrap_or_default(),
+    _ => json!({ "kind": "Other" }),
+  };
+  v["message"] = json!(e.to_string());
+  v
+}
+
 /// Clean up our logs directory.
 fn clean() -> std::io::Result<()> {
   std::fs::remove_dir_all("./logs/")?;
   Ok(())
 }
 
-/// Print help for the user.
-fn help() {
-  println!(
-    "usage:
-    hm [-h] | [-t|--task] [<task>] | --clean | [-c|--config] [<config>]
-    -t | --task             > run specific named task
-    -h | --help             > this help message
-    clean                   > removes the contents of the log directory
-    log                     > return the path of the most recent log file (use with your editor - `nvr (hm log)`)
-    -c | --config [config]  > Optional.
-    if config is not specified, default location of ~/.config/homemaker/config.toml is assumed."
-  );
-  exit(0)
+#[cfg(test)]
+mod cli_test {
+  use super::*;
+
+  #[test]
+  fn apply_options() {
+    let cli = parse(["hm", "-t", "zt,slop", "-n"]).unwrap();
+    assert!(cli.command.is_none());
+    assert_eq!(vec!["zt", "slop"], cli.apply.task);
+    assert!(cli.apply.dry_run);
+    match parse(["hm", "apply", "-c", "cfg.toml", "--dry-run"])
+      .unwrap()
+      .command
+    {
+      Some(Cmd::Apply(a)) => assert!(a.dry_run),
+      c => panic!("expected apply, got {:?}", c),
+    }
+    // before the subcommand they'd be thrown away
+    assert!(parse(["hm", "--dry-run", "apply", "-c", "cfg.toml"]).is_err());
+    assert!(parse(["hm", "-t", "deb", "apply", "-n"]).is_err());
+    assert!(parse(["hm", "-t", "zt", "status"]).is_err());
+    // global ones go anywhere
+    assert_eq!(
+      Some(String::from("cfg.toml")),
+      parse(["hm", "status", "-c", "cfg.toml"]).unwrap().config
+    );
+  }
 }
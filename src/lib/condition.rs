@@ -0,0 +1,357 @@
+//! The little expression language behind `when =`, for rules no fixed field can capture -
+//! "only link the sway config if sway is installed", "only on my work account".
+//!
+//! ``` text
+//! when = "which('sway') && env('XDG_SESSION_TYPE') == 'wayland'"
+//! when = "user == 'dan' || exists('~/.work')"
+//! when = "!(distro == 'arch')"
+//! ```
+//!
+//! * Facts: `hostname`, `user`, `os` (`linux`, `windows`), `distro` (`ubuntu`, `fedora`...),
+//!   `version` (the distro's `VERSION_ID`) and `arch` (`x86_64`, `aarch64`...).
+//! * Functions: `env('NAME')` is an environment variable (empty if it isn't set),
+//!   `which('nvim')` is whether a command is on our `$PATH`, and `exists('~/path')`
+//!   is whether a file or directory is there.
+//! * Strings are in single or double quotes; `true` and `false` are what you'd think.
+//! * `==` and `!=` compare strings, and `!`, `&&`, `||` and parentheses do the rest.
+//!   Anything that isn't a comparison counts as true if it's a non-empty string.
+use std::{
+  env, fmt,
+  path::{Path, PathBuf},
+};
+
+use crate::config::{LinuxDistro, Platform, OS};
+use crate::facts::current_user;
+
+///
+/// A parsed `when =` expression.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Condition {
+  expr: Expr,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Expr {
+  Str(String),
+  Bool(bool),
+  Fact(String),
+  Call(String, String),
+  Not(Box<Expr>),
+  And(Box<Expr>, Box<Expr>),
+  Or(Box<Expr>, Box<Expr>),
+  Eq(Box<Expr>, Box<Expr>),
+  Ne(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+  Str(String),
+  Ident(String),
+  LParen,
+  RParen,
+  Comma,
+  Not,
+  And,
+  Or,
+  Eq,
+  Ne,
+}
+
+const FACTS: [&str; 6] = ["hostname", "user", "os", "distro", "version", "arch"];
+const FUNCTIONS: [&str; 3] = ["env", "which", "exists"];
+
+impl Condition {
+  /// Parse a `when =` expression, or say what's wrong with it.
+  pub fn parse(s: &str) -> Result<Condition, String> {
+    let mut p = Parser {
+      tokens: tokenize(s)?,
+      pos: 0,
+    };
+    let expr = p.or()?;
+    match p.peek() {
+      None => Ok(Condition { expr }),
+      Some(t) => Err(format!("unexpected {} at the end", t)),
+    }
+  }
+
+  /// Does this condition hold on this machine?
+  pub fn holds(&self, platform: &Platform) -> bool {
+    truthy(&eval(&self.expr, platform))
+  }
+}
+
+impl fmt::Display for Token {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Token::Str(s) => write!(f, "'{}'", s),
+      Token::Ident(s) => write!(f, "`{}`", s),
+      Token::LParen => write!(f, "`(`"),
+      Token::RParen => write!(f, "`)`"),
+      Token::Comma => write!(f, "`,`"),
+      Token::Not => write!(f, "`!`"),
+      Token::And => write!(f, "`&&`"),
+      Token::Or => write!(f, "`||`"),
+      Token::Eq => write!(f, "`==`"),
+      Token::Ne => write!(f, "`!=`"),
+    }
+  }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+  let mut tokens = Vec::new();
+  let mut chars = s.chars().peekable();
+  while let Some(c) = chars.next() {
+    let t = match c {
+      c if c.is_whitespace() => continue,
+      '(' => Token::LParen,
+      ')' => Token::RParen,
+      ',' => Token::Comma,
+      '!' if chars.peek() == Some(&'=') => {
+        chars.next();
+        Token::Ne
+      }
+      '!' => Token::Not,
+      '=' if chars.next() == Some('=') => Token::Eq,
+      '&' if chars.next() == Some('&') => Token::And,
+      '|' if chars.next() == Some('|') => Token::Or,
+      '\'' | '"' => {
+        let mut lit = String::new();
+        loop {
+          match chars.next() {
+            Some(q) if q == c => break,
+            Some(o) => lit.push(o),
+            None => return Err(format!("unterminated string {}{}", c, lit)),
+          }
+        }
+        Token::Str(lit)
+      }
+      c if c.is_alphabetic() || c == '_' => {
+        let mut id = String::from(c);
+        while let Some(&o) = chars.peek() {
+          if !(o.is_alphanumeric() || o == '_') {
+            break;
+          }
+          id.push(o);
+          chars.next();
+        }
+        Token::Ident(id)
+      }
+      o => return Err(format!("unexpected `{}`", o)),
+    };
+    tokens.push(t);
+  }
+  Ok(tokens)
+}
+
+struct Parser {
+  tokens: Vec<Token>,
+  pos: usize,
+}
+
+impl Parser {
+  fn peek(&self) -> Option<&Token> {
+    self.tokens.get(self.pos)
+  }
+
+  fn next(&mut self) -> Option<Token> {
+    let t = self.tokens.get(self.pos).cloned();
+    self.pos += 1;
+    t
+  }
+
+  fn expect(&mut self, want: Token) -> Result<(), String> {
+    match self.next() {
+      Some(t) if t == want => Ok(()),
+      Some(t) => Err(format!("expected {}, found {}", want, t)),
+      None => Err(format!("expected {}, found the end", want)),
+    }
+  }
+
+  fn or(&mut self) -> Result<Expr, String> {
+    let mut e = self.and()?;
+    while self.peek() == Some(&Token::Or) {
+      self.next();
+      e = Expr::Or(Box::new(e), Box::new(self.and()?));
+    }
+    Ok(e)
+  }
+
+  fn and(&mut self) -> Result<Expr, String> {
+    let mut e = self.comparison()?;
+    while self.peek() == Some(&Token::And) {
+      self.next();
+      e = Expr::And(Box::new(e), Box::new(self.comparison()?));
+    }
+    Ok(e)
+  }
+
+  fn comparison(&mut self) -> Result<Expr, String> {
+    let e = self.unary()?;
+    match self.peek() {
+      Some(Token::Eq) => {
+        self.next();
+        Ok(Expr::Eq(Box::new(e), Box::new(self.unary()?)))
+      }
+      Some(Token::Ne) => {
+        self.next();
+        Ok(Expr::Ne(Box::new(e), Box::new(self.unary()?)))
+      }
+      _ => Ok(e),
+    }
+  }
+
+  fn unary(&mut self) -> Result<Expr, String> {
+    match self.next() {
+      Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary()?))),
+      Some(Token::LParen) => {
+        let e = self.or()?;
+        self.expect(Token::RParen)?;
+        Ok(e)
+      }
+      Some(Token::Str(s)) => Ok(Expr::Str(s)),
+      Some(Token::Ident(id)) if id == "true" => Ok(Expr::Bool(true)),
+      Some(Token::Ident(id)) if id == "false" => Ok(Expr::Bool(false)),
+      Some(Token::Ident(id)) if FUNCTIONS.contains(&id.as_str()) => {
+        self.expect(Token::LParen)?;
+        let arg = match self.next() {
+          Some(Token::Str(s)) => s,
+          Some(t) => return Err(format!("`{}()` takes a string, found {}", id, t)),
+          None => return Err(format!("`{}()` takes a string, found the end", id)),
+        };
+        self.expect(Token::RParen)?;
+        Ok(Expr::Call(id, arg))
+      }
+      Some(Token::Ident(id)) if FACTS.contains(&id.as_str()) => Ok(Expr::Fact(id)),
+      Some(Token::Ident(id)) => Err(format!(
+        "don't know `{}`; try one of {} or {}()",
+        id,
+        FACTS.join(", "),
+        FUNCTIONS.join("(), ")
+      )),
+      Some(t) => Err(format!("unexpected {}", t)),
+      None => Err(String::from("unexpected end")),
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Value {
+  Str(String),
+  Bool(bool),
+}
+
+fn truthy(v: &Value) -> bool {
+  match v {
+    Value::Bool(b) => *b,
+    Value::Str(s) => !s.is_empty(),
+  }
+}
+
+fn as_string(v: Value) -> String {
+  match v {
+    Value::Bool(b) => b.to_string(),
+    Value::Str(s) => s,
+  }
+}
+
+fn eval(e: &Expr, platform: &Platform) -> Value {
+  match e {
+    Expr::Str(s) => Value::Str(s.clone()),
+    Expr::Bool(b) => Value::Bool(*b),
+    Expr::Fact(f) => Value::Str(fact(f, platform)),
+    Expr::Call(f, arg) => match f.as_str() {
+      "env" => Value::Str(env::var(arg).unwrap_or_default()),
+      "which" => Value::Bool(which(arg).is_some()),
+      _ => Value::Bool(Path::new(&shellexpand::tilde(arg).to_string()).exists()),
+    },
+    Expr::Not(a) => Value::Bool(!truthy(&eval(a, platform))),
+    Expr::And(a, b) => Value::Bool(truthy(&eval(a, platform)) && truthy(&eval(b, platform))),
+    Expr::Or(a, b) => Value::Bool(truthy(&eval(a, platform)) || truthy(&eval(b, platform))),
+    Expr::Eq(a, b) => Value::Bool(as_string(eval(a, platform)) == as_string(eval(b, platform))),
+    Expr::Ne(a, b) => Value::Bool(as_string(eval(a, platform)) != as_string(eval(b, platform))),
+  }
+}
+
+fn fact(f: &str, platform: &Platform) -> String {
+  match f {
+    "hostname" => platform.hostname.clone(),
+    "user" => current_user(),
+    "os" => match &platform.os {
+      OS::Linux(_) => String::from("linux"),
+      o => o.to_string(),
+    },
+    "distro" => match &platform.os {
+      OS::Linux(LinuxDistro::Generic) | OS::Windows | OS::Unknown => String::new(),
+      OS::Linux(d) => d.to_string(),
+    },
+    "version" => platform.version.clone(),
+    _ => platform.arch.clone(),
+  }
+}
+
+/// Where `cmd` is on our `$PATH`, if it's there at all.
+/// Anything with a `/` in it is taken as a path as-is.
+pub(crate) fn which(cmd: &str) -> Option<PathBuf> {
+  if cmd.contains('/') {
+    let p = PathBuf::from(shellexpand::tilde(cmd).to_string());
+    return if p.is_file() { Some(p) } else { None };
+  }
+  let exts: Vec<String> = if cfg!(windows) {
+    env::var("PATHEXT")
+      .unwrap_or_else(|_e| String::from(".EXE;.BAT;.CMD"))
+      .split(';')
+      .map(String::from)
+      .chain(std::iter::once(String::new()))
+      .collect()
+  } else {
+    vec![String::new()]
+  };
+  env::split_paths(&env::var_os("PATH")?)
+    .flat_map(|d| exts.iter().map(move |e| d.join(format!("{}{}", cmd, e))))
+    .find(|p| p.is_file())
+}
+
+#[cfg(test)]
+mod condition_test {
+  use super::*;
+
+  fn ubuntu() -> Platform {
+    Platform {
+      hostname: String::from("laptop-01"),
+      arch: String::from("x86_64"),
+      ..Platform::from_os_release("ubuntu", "debian", "22.04")
+    }
+  }
+
+  fn holds(s: &str) -> bool {
+    Condition::parse(s).unwrap().holds(&ubuntu())
+  }
+
+  #[test]
+  fn facts_and_operators() {
+    assert!(holds("distro == 'ubuntu' && os == \"linux\""));
+    assert!(holds("!(arch == 'aarch64') || hostname == 'nope'"));
+    assert!(holds("version != '20.04' && true"));
+    assert!(!holds("hostname == 'desktop' || false"));
+  }
+
+  #[test]
+  fn functions() {
+    assert!(holds("env('PATH')"));
+    assert!(!holds("env('HM_SURELY_NOT_SET_ANYWHERE')"));
+    assert!(holds("env('HM_SURELY_NOT_SET_ANYWHERE') == ''"));
+    assert!(holds("exists('Cargo.toml') && !exists('Cargo.nope')"));
+    assert!(holds("which('cargo')"));
+    assert!(!holds("which('hm-surely-not-a-command')"));
+  }
+
+  #[test]
+  fn bad_expressions() {
+    assert!(Condition::parse("distro = 'ubuntu'").is_err());
+    assert!(Condition::parse("which(sway)").is_err());
+    assert!(Condition::parse("(user == 'dan'").is_err());
+    assert!(Condition::parse("shoe_size == '11'").is_err());
+    assert!(Condition::parse("'unterminated").is_err());
+  }
+}
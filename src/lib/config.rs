@@ -6,8 +6,9 @@ extern crate serde;
 extern crate strum_macros;
 extern crate toml;
 
+use log::warn;
 use serde::{Deserialize, Deserializer, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
 use std::{
   fmt, fs,
@@ -16,10 +17,10 @@ use std::{
   string::String,
 };
 //use strum;
-use strum_macros::EnumString;
+use strum_macros::{Display, EnumString};
 use toml::value;
 
-use super::hmerror::{HMError, Result as HMResult};
+use super::hmerror::{ErrorKind as hmek, HMError, Result as HMResult};
 
 ///
 /// Allow us to communicate meaningfully back to `main()` thread.
@@ -29,9 +30,11 @@ pub struct Worker {
   pub name: String,
   pub status: Option<i32>,
   pub completed: bool,
+  /// The last few lines the task printed, once it's `completed`.
+  pub output: Vec<String>,
 }
 
-impl<'a> Worker {
+impl Worker {
   // i'll get to you
   #[allow(dead_code)]
   pub fn new() -> Worker {
@@ -39,11 +42,12 @@ impl<'a> Worker {
       name: String::from(""),
       status: Some(1),
       completed: false,
+      output: Vec::new(),
     }
   }
 }
 
-impl<'a> Default for Worker {
+impl Default for Worker {
   fn default() -> Self {
     Self::new()
   }
@@ -56,33 +60,87 @@ impl PartialEq for Worker {
 }
 impl Eq for Worker {}
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, PartialOrd, Ord, Hash, EnumString)]
+#[derive(
+  Serialize, Deserialize, Clone, Debug, Default, PartialEq, PartialOrd, Ord, Hash, EnumString,
+)]
 #[strum(serialize_all = "snake_case")]
 pub enum OS {
   Windows,
+  #[default]
   Unknown,
   Linux(LinuxDistro),
 }
 
-impl Default for OS {
-  fn default() -> Self {
-    OS::Unknown
-  }
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd, Ord, Hash, EnumString)]
+#[derive(
+  Serialize,
+  Deserialize,
+  Debug,
+  Clone,
+  Default,
+  PartialEq,
+  PartialOrd,
+  Ord,
+  Hash,
+  EnumString,
+  Display,
+)]
 #[strum(serialize_all = "snake_case")]
 pub enum LinuxDistro {
   Fedora,
   Debian,
   Ubuntu,
   Arch,
+  Alpine,
+  #[strum(serialize = "opensuse")]
+  OpenSuse,
+  #[strum(serialize = "nixos")]
+  NixOS,
+  Gentoo,
+  Void,
+  Rhel,
+  #[default]
   Generic,
 }
 
-impl Default for LinuxDistro {
-  fn default() -> Self {
-    LinuxDistro::Generic
+impl LinuxDistro {
+  /// Which of ours an `/etc/os-release` `ID` (or `ID_LIKE` entry) is, if any.
+  pub fn from_id(id: &str) -> Option<LinuxDistro> {
+    match id.to_ascii_lowercase().as_str() {
+      "fedora" => Some(LinuxDistro::Fedora),
+      "debian" => Some(LinuxDistro::Debian),
+      "ubuntu" => Some(LinuxDistro::Ubuntu),
+      "arch" | "archarm" => Some(LinuxDistro::Arch),
+      "alpine" => Some(LinuxDistro::Alpine),
+      "suse" | "sles" => Some(LinuxDistro::OpenSuse),
+      o if o.starts_with("opensuse") => Some(LinuxDistro::OpenSuse),
+      "nixos" => Some(LinuxDistro::NixOS),
+      "gentoo" => Some(LinuxDistro::Gentoo),
+      "void" => Some(LinuxDistro::Void),
+      "rhel" => Some(LinuxDistro::Rhel),
+      _ => None,
+    }
+  }
+
+  /// What its `ID_LIKE` usually says - what we assume when there's no `/etc/os-release`
+  /// to ask, like when we're only pretending to be it.
+  pub fn family(&self) -> Vec<LinuxDistro> {
+    match self {
+      LinuxDistro::Ubuntu => vec![LinuxDistro::Debian],
+      LinuxDistro::Rhel => vec![LinuxDistro::Fedora],
+      _ => Vec::new(),
+    }
+  }
+}
+
+/// The same way we'd write it in `config.toml`: `windows`, `linux`, `linux::ubuntu`.
+impl fmt::Display for OS {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      OS::Windows => write!(f, "windows"),
+      OS::Unknown => write!(f, "unknown"),
+      OS::Linux(LinuxDistro::Generic) => write!(f, "linux"),
+      OS::Linux(d) => write!(f, "linux::{}", d),
+    }
   }
 }
 
@@ -91,30 +149,318 @@ impl Eq for OS {}
 impl Eq for LinuxDistro {}
 
 ///
-/// Windows or Linux? If Linux, let's determine our distro, because package managers and stuff.
+/// What we know about the machine we're running on, as far as `os =` is concerned.
+/// `like` comes from `ID_LIKE` in `/etc/os-release`: Pop!_OS is like Ubuntu and Debian,
+/// Rocky is like RHEL and Fedora, Manjaro is like Arch.
 ///
-pub fn determine_os() -> OS {
-  match sys_info::os_type() {
-    Ok(s) => match s.to_ascii_lowercase().as_str() {
-      "linux" => match sys_info::linux_os_release() {
-        Ok(l) => {
-          let a: String = l.name.unwrap().to_ascii_lowercase();
-          if a.contains("fedora") {
-            OS::Linux(LinuxDistro::Fedora)
-          } else if a.contains("debian") {
-            OS::Linux(LinuxDistro::Debian)
-          } else if a.contains("ubuntu") {
-            OS::Linux(LinuxDistro::Ubuntu)
-          } else {
-            OS::Unknown
-          }
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Platform {
+  pub os: OS,
+  pub like: Vec<LinuxDistro>,
+  /// `VERSION_ID` from `/etc/os-release` - `22.04`, `39` and so on. Empty if we don't know.
+  pub version: String,
+  /// The CPU architecture we were built for - `x86_64`, `aarch64`...
+  pub arch: String,
+  pub hostname: String,
+}
+
+impl Platform {
+  /// Work out what we're running on. `HM_OS` (`linux::arch`, `linux::ubuntu::22.04`, `windows`)
+  /// takes the place of whatever OS we detect, so we can see what a config does elsewhere.
+  pub fn detect() -> Platform {
+    let os_only = |os: OS| Platform {
+      os,
+      ..Default::default()
+    };
+    let mut p = match sys_info::os_type() {
+      Ok(s) => match s.to_ascii_lowercase().as_str() {
+        "linux" => match sys_info::linux_os_release() {
+          Ok(l) => Platform::from_os_release(
+            l.id(),
+            l.id_like.as_deref().unwrap_or(""),
+            l.version_id.as_deref().unwrap_or(""),
+          ),
+          Err(_e) => os_only(OS::Linux(LinuxDistro::Generic)),
+        },
+        "windows" => os_only(OS::Windows),
+        _ => Platform::default(),
+      },
+      Err(_e) => Platform::default(),
+    };
+    p.arch = std::env::consts::ARCH.to_string();
+    p.hostname = sys_info::hostname().unwrap_or_default();
+    match std::env::var("HM_OS") {
+      Ok(o) if !o.is_empty() => match p.clone().pretending_to_be(&o) {
+        Ok(q) => q,
+        Err(e) => {
+          warn!("Ignoring HM_OS `{}`, it isn't a platform we know: {}", o, e);
+          p
         }
-        Err(_e) => OS::Unknown,
       },
-      "windows" => OS::Windows,
-      _ => OS::Unknown,
-    },
-    Err(_e) => OS::Unknown,
+      _ => p,
+    }
+  }
+
+  /// This platform, but with the OS (and distro version, if there is one)
+  /// from an `os =` style string instead of what we detected. The distro brings
+  /// its usual family along: pretending to be Ubuntu still gets you Debian's objects.
+  pub fn pretending_to_be(self, os: &str) -> std::result::Result<Platform, strum::ParseError> {
+    let f = OsFilter::from_str(os)?;
+    let like = match &f.os {
+      OS::Linux(d) => d.family(),
+      _ => Vec::new(),
+    };
+    Ok(Platform {
+      os: f.os,
+      like,
+      version: f.version.map(|v| v.version).unwrap_or_default(),
+      ..self
+    })
+  }
+
+  /// Our platform, given the `ID`, `ID_LIKE` and `VERSION_ID` from `/etc/os-release`.
+  /// If we don't know `ID` we go with the first thing in `ID_LIKE` we do know,
+  /// and if we don't know any of those either, we're just `Linux(Generic)`.
+  pub fn from_os_release(id: &str, id_like: &str, version_id: &str) -> Platform {
+    let like: Vec<LinuxDistro> = id_like
+      .split_whitespace()
+      .filter_map(LinuxDistro::from_id)
+      .collect();
+    let distro = LinuxDistro::from_id(id)
+      .or_else(|| like.first().cloned())
+      .unwrap_or_default();
+    Platform {
+      os: OS::Linux(distro),
+      like,
+      version: version_id.to_string(),
+      ..Default::default()
+    }
+  }
+
+  /// Does an object restricted to `target` belong here?
+  /// `linux` on its own matches any distro, and `linux::<distro>` matches
+  /// that distro or anything like it - as long as our version fits, if it names one.
+  /// Our version is our own distro's, so a filter with a version never matches
+  /// through `like`: Ubuntu 22.04 isn't Debian 22.
+  pub fn matches(&self, target: &OsFilter) -> bool {
+    // does it match, and is it our own version numbering it's talking about?
+    let (os, ours) = match (&target.os, &self.os) {
+      (t, o) if t == o => (true, true),
+      (OS::Linux(LinuxDistro::Generic), OS::Linux(_)) => (true, true),
+      (OS::Linux(d), OS::Linux(_)) => (self.like.contains(d), false),
+      _ => (false, false),
+    };
+    match &target.version {
+      Some(v) => os && ours && v.matches(&self.version),
+      None => os,
+    }
+  }
+
+  /// Are we one of these architectures? No architectures at all means we don't care.
+  pub fn matches_arch(&self, arches: &[String]) -> bool {
+    arches.is_empty()
+      || arches
+        .iter()
+        .any(|a| normalize_arch(a) == normalize_arch(&self.arch))
+  }
+
+  /// Does our hostname match any of these glob patterns (`laptop-*`, `build0?`)?
+  /// No patterns at all means we don't care.
+  pub fn matches_host(&self, hosts: &[String]) -> bool {
+    let opts = glob::MatchOptions {
+      case_sensitive: false,
+      ..Default::default()
+    };
+    hosts.is_empty()
+      || hosts.iter().any(|h| match glob::Pattern::new(h) {
+        Ok(p) => p.matches_with(&self.hostname, opts),
+        Err(_e) => h.eq_ignore_ascii_case(&self.hostname),
+      })
+  }
+}
+
+/// Everybody's got their own name for the same two architectures.
+fn normalize_arch(a: &str) -> String {
+  match a.to_ascii_lowercase().as_str() {
+    "amd64" | "x64" | "x86-64" => String::from("x86_64"),
+    "arm64" => String::from("aarch64"),
+    // what Rust calls 32-bit ARM, whatever flavour it is
+    "armv7" | "armv7l" | "armhf" => String::from("arm"),
+    o => o.to_string(),
+  }
+}
+
+///
+/// How a distro's version has to compare to the one in an `os =` filter.
+///
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum VersionOp {
+  Eq,
+  Ge,
+  Gt,
+  Le,
+  Lt,
+}
+
+///
+/// The version part of `linux::ubuntu>=22.04` or `linux::fedora::39`.
+///
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VersionReq {
+  pub op: VersionOp,
+  pub version: String,
+}
+
+impl VersionReq {
+  /// Versions compare a dot-separated piece at a time, numerically where we can -
+  /// `22.4` is `22.04`, whichever way we're comparing.
+  /// `Eq` only cares about as many pieces as it was given, so `22` matches `22.04`.
+  /// An unknown version (empty) never matches.
+  pub fn matches(&self, ours: &str) -> bool {
+    if ours.is_empty() {
+      return false;
+    }
+    let piece = |h: &str, w: &str| match (h.parse::<u64>(), w.parse::<u64>()) {
+      (Ok(h), Ok(w)) => h.cmp(&w),
+      _ => h.cmp(w),
+    };
+    let want: Vec<&str> = self.version.split('.').collect();
+    let have: Vec<&str> = ours.split('.').collect();
+    if self.op == VersionOp::Eq {
+      return have.len() >= want.len() && want.iter().zip(&have).all(|(w, h)| piece(h, w).is_eq());
+    }
+    let mut ord = std::cmp::Ordering::Equal;
+    for i in 0..want.len().max(have.len()) {
+      ord = piece(have.get(i).unwrap_or(&"0"), want.get(i).unwrap_or(&"0"));
+      if ord != std::cmp::Ordering::Equal {
+        break;
+      }
+    }
+    match self.op {
+      VersionOp::Ge => ord.is_ge(),
+      VersionOp::Gt => ord.is_gt(),
+      VersionOp::Le => ord.is_le(),
+      VersionOp::Lt => ord.is_lt(),
+      VersionOp::Eq => ord.is_eq(),
+    }
+  }
+}
+
+///
+/// An `os =` entry: `windows`, `linux`, `linux::<distro>`, and optionally a version
+/// requirement on the distro - `linux::ubuntu>=22.04` (also `>`, `<=`, `<`, `=`),
+/// or `linux::fedora::39` for exactly that version.
+///
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OsFilter {
+  pub os: OS,
+  pub version: Option<VersionReq>,
+}
+
+impl FromStr for OsFilter {
+  type Err = strum::ParseError;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    let _a: Vec<&str> = s.split("::").collect::<Vec<&str>>();
+    if _a.len() == 1 {
+      return Ok(OsFilter {
+        os: OS::from_str(_a[0].trim().to_lowercase().as_str())?,
+        version: None,
+      });
+    }
+    let mut distro: &str = _a[1];
+    let mut version: Option<VersionReq> = None;
+    // longest operators first, so `>=` isn't mistaken for `>`
+    for (op_str, op) in [
+      (">=", VersionOp::Ge),
+      ("<=", VersionOp::Le),
+      (">", VersionOp::Gt),
+      ("<", VersionOp::Lt),
+      ("=", VersionOp::Eq),
+    ] {
+      if let Some(i) = distro.find(op_str) {
+        version = Some(VersionReq {
+          op,
+          version: distro[i + op_str.len()..].trim().to_string(),
+        });
+        distro = &distro[..i];
+        break;
+      }
+    }
+    if let Some(v) = _a.get(2) {
+      version = Some(VersionReq {
+        op: VersionOp::Eq,
+        version: v.trim().to_string(),
+      });
+    }
+    Ok(OsFilter {
+      os: OS::Linux(LinuxDistro::from_str(
+        distro.trim().to_lowercase().as_str(),
+      )?),
+      version,
+    })
+  }
+}
+
+impl fmt::Display for OsFilter {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.os)?;
+    if let Some(v) = &self.version {
+      let op = match v.op {
+        VersionOp::Eq => "=",
+        VersionOp::Ge => ">=",
+        VersionOp::Gt => ">",
+        VersionOp::Le => "<=",
+        VersionOp::Lt => "<",
+      };
+      write!(f, "{}{}", op, v.version)?;
+    }
+    Ok(())
+  }
+}
+
+///
+/// Windows or Linux? If Linux, let's determine our distro, because package managers and stuff.
+///
+pub fn determine_os() -> OS {
+  Platform::detect().os
+}
+
+///
+/// What runs our `solution`s. Either the name of a shell or interpreter (`sh`, `zsh`,
+/// `fish`, `python3`...), which gets `-c <solution>` (or just the path to a `script`),
+/// or a whole custom argv, which gets the solution (or script path) tacked on the end.
+/// ``` text
+/// shell = 'zsh'
+/// shell = ['nix-shell', '--run']
+/// ```
+///
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum Shell {
+  Named(String),
+  Argv(Vec<String>),
+}
+
+impl Default for Shell {
+  fn default() -> Self {
+    Shell::Named(String::from("bash"))
+  }
+}
+
+impl Shell {
+  /// The full argv to run `solution` - or, if `script` is set, the script file at that path.
+  pub fn argv(&self, solution: &str, script: bool) -> Vec<String> {
+    match self {
+      Shell::Named(n) if script => vec![n.to_owned(), solution.to_owned()],
+      Shell::Named(n) => vec![n.to_owned(), String::from("-c"), solution.to_owned()],
+      Shell::Argv(v) if v.is_empty() => Shell::default().argv(solution, script),
+      Shell::Argv(v) => {
+        let mut a = v.clone();
+        a.push(solution.to_owned());
+        a
+      }
+    }
   }
 }
 
@@ -133,20 +479,87 @@ pub struct ManagedObject {
   pub task: String,
   pub solution: String,
   pub dependencies: Vec<String>,
+  /// Objects to go after, if they're in this run - unlike `dependencies`, it's fine
+  /// for them to be missing, not for this machine, or to fail.
+  pub after: Vec<String>,
+  /// Set when our `state` says this task already completed and nothing's changed since.
   pub satisfied: bool,
-  pub os: Option<OS>,
+  /// Platforms this object is for. Empty means all of them.
+  pub os: Vec<OsFilter>,
+  /// Platforms this object is definitely not for.
+  pub not_os: Vec<OsFilter>,
+  /// CPU architectures this object is for. Empty means all of them.
+  pub arch: Vec<String>,
+  /// Glob patterns of hostnames this object is for. Empty means all of them.
+  pub hosts: Vec<String>,
+  /// A `condition` expression that has to hold on this machine. Empty means it always does.
+  pub when: String,
+  /// For picking out groups of objects with `--tag`, `--skip-tag` and profiles.
+  pub tags: Vec<String>,
   pub force: bool,
   pub post: String,
+  /// Directory to run `solution` (and `post`) from.
+  pub cwd: String,
+  /// Extra environment variables for `solution` (and `post`).
+  pub env: HashMap<String, String>,
+  /// What runs `solution`, `script` and `post`. Falls back to the config-wide `shell`, then `bash`.
+  pub shell: Shell,
+  /// A script file to run instead of an inline `solution`.
+  pub script: String,
+  /// Where this object comes in `config.toml`. When the dependencies don't decide
+  /// what goes first, this does.
+  pub order: usize,
 }
 
 impl ManagedObject {
-  /// quite simply, if we're a task, we'll have a `solution`.
+  /// quite simply, if we're a task, we'll have a `solution` (or a `script`).
   pub fn is_task(&self) -> bool {
-    !self.solution.is_empty()
+    !self.solution.is_empty() || !self.script.is_empty()
   }
   pub fn set_satisfied(&mut self) {
     self.satisfied = true;
   }
+
+  /// What we'll do with this object and when, a line per thing worth saying - for humans.
+  pub fn details(&self) -> Vec<String> {
+    let mut d: Vec<String> = Vec::new();
+    if self.is_task() {
+      if self.script.is_empty() {
+        d.push(format!("runs `{}`", self.solution));
+      } else {
+        d.push(format!("runs script {}", self.script));
+      }
+    } else {
+      d.push(format!(
+        "{} {} → {}{}",
+        self.method,
+        self.source,
+        self.destination,
+        if self.force { " (force)" } else { "" }
+      ));
+    }
+    let join = |v: &[OsFilter]| {
+      v.iter()
+        .map(|o| o.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+    };
+    let fields = [
+      ("depends on", self.dependencies.join(", ")),
+      ("after", self.after.join(", ")),
+      ("os", join(&self.os)),
+      ("not os", join(&self.not_os)),
+      ("arch", self.arch.join(", ")),
+      ("hosts", self.hosts.join(", ")),
+      ("when", self.when.clone()),
+      ("tags", self.tags.join(", ")),
+      ("post", self.post.clone()),
+    ];
+    for (k, v) in fields.iter().filter(|(_, v)| !v.is_empty()) {
+      d.push(format!("{}: {}", k, v));
+    }
+    d
+  }
 }
 impl PartialEq for ManagedObject {
   fn eq(&self, other: &Self) -> bool {
@@ -166,7 +579,7 @@ impl fmt::Display for ManagedObject {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     write!(
       f,
-      "{} {} {} {} {} {} {} {} {:?} {:?} {}",
+      "{} {} {} {} {} {} {} {} {:?} {:?} {} {} {:?} {:?} {}",
       self.name,
       self.file,
       self.source,
@@ -177,7 +590,11 @@ impl fmt::Display for ManagedObject {
       self.satisfied,
       self.os,
       self.force,
-      self.post
+      self.post,
+      self.cwd,
+      self.env,
+      self.shell,
+      self.script
     )
   }
 }
@@ -193,10 +610,21 @@ impl Default for ManagedObject {
       task: String::from(""),
       solution: String::from(""),
       dependencies: Vec::new(),
+      after: Vec::new(),
       satisfied: false,
-      os: None,
+      os: Vec::new(),
+      not_os: Vec::new(),
+      arch: Vec::new(),
+      hosts: Vec::new(),
+      when: String::from(""),
+      tags: Vec::new(),
       force: false,
       post: "".to_string(),
+      cwd: "".to_string(),
+      env: HashMap::new(),
+      shell: Shell::default(),
+      script: "".to_string(),
+      order: 0,
     }
   }
 }
@@ -207,6 +635,85 @@ impl Default for ManagedObject {
 pub struct Config {
   #[serde(rename = "obj", deserialize_with = "deserialize_files")]
   pub files: Vec<(String, value::Value)>,
+  /// What runs every object's `solution`, unless the object says otherwise.
+  #[serde(default)]
+  pub shell: Option<Shell>,
+  /// Named selections of objects, by tag - `[profiles.server]`.
+  #[serde(default)]
+  pub profiles: HashMap<String, Profile>,
+}
+
+///
+/// A named selection of objects: `hm --profile server` is `--tag` and `--skip-tag`
+/// for every one of its `tags` and `skip_tags`.
+///
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Profile {
+  #[serde(default)]
+  pub tags: Vec<String>,
+  #[serde(default)]
+  pub skip_tags: Vec<String>,
+}
+
+///
+/// Everything but the objects named in `exclude`, and whatever needs them -
+/// it couldn't run without them.
+///
+pub fn exclude(
+  mut mos: HashMap<String, ManagedObject>,
+  exclude: &[String],
+) -> HashMap<String, ManagedObject> {
+  if exclude.is_empty() {
+    return mos;
+  }
+  let mut gone: HashSet<String> = exclude.iter().cloned().collect();
+  loop {
+    let needs_gone: Vec<String> = mos
+      .values()
+      .filter(|m| !gone.contains(&m.name) && m.dependencies.iter().any(|d| gone.contains(d)))
+      .map(|m| m.name.clone())
+      .collect();
+    if needs_gone.is_empty() {
+      break;
+    }
+    for n in &needs_gone {
+      warn!("Leaving out {} too, it depends on something excluded.", n);
+    }
+    gone.extend(needs_gone);
+  }
+  mos.retain(|n, _| !gone.contains(n));
+  mos
+}
+
+///
+/// Only the objects with one of `tags` (all of them, if there aren't any `tags`) and none of
+/// `skip_tags` - plus everything those depend on, whatever its tags, so they've still got
+/// what they need.
+///
+pub fn select_by_tags(
+  mut mos: HashMap<String, ManagedObject>,
+  tags: &[String],
+  skip_tags: &[String],
+) -> HashMap<String, ManagedObject> {
+  if tags.is_empty() && skip_tags.is_empty() {
+    return mos;
+  }
+  let mut wanted: Vec<String> = mos
+    .values()
+    .filter(|m| tags.is_empty() || m.tags.iter().any(|t| tags.contains(t)))
+    .filter(|m| !m.tags.iter().any(|t| skip_tags.contains(t)))
+    .map(|m| m.name.clone())
+    .collect();
+  let mut keep: HashSet<String> = HashSet::new();
+  while let Some(n) = wanted.pop() {
+    if keep.insert(n.clone()) {
+      if let Some(m) = mos.get(&n) {
+        wanted.extend(m.dependencies.iter().cloned());
+      }
+    }
+  }
+  mos.retain(|n, _| keep.contains(n));
+  mos
 }
 
 /*
@@ -217,10 +724,22 @@ impl Default for Config {
 }
 */
 
+/// Files, then tasks, by name - each with its `details()`.
 impl fmt::Display for Config {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     let mos = Config::as_managed_objects(self.clone());
-    write!(f, "{:#?}", mos)
+    let mut mos: Vec<&ManagedObject> = mos.values().collect();
+    mos.sort_by(|a, b| (a.is_task(), &a.name).cmp(&(b.is_task(), &b.name)));
+    for (i, mo) in mos.iter().enumerate() {
+      if i == 0 || mos[i - 1].is_task() != mo.is_task() {
+        writeln!(f, "{}:", if mo.is_task() { "tasks" } else { "files" })?;
+      }
+      writeln!(f, "  {}", mo.name)?;
+      for d in mo.details() {
+        writeln!(f, "    {}", d)?;
+      }
+    }
+    Ok(())
   }
 }
 
@@ -240,9 +759,11 @@ impl Config {
     config
       .files
       .iter()
-      .map(|(name, val)| {
+      .enumerate()
+      .map(|(order, (name, val))| {
         let mut mo = ManagedObject {
           name: name.to_owned(),
+          order,
           ..Default::default()
         };
         mo.name = name.to_owned();
@@ -265,28 +786,68 @@ impl Config {
           let _f = _x.as_array().unwrap();
           mo.dependencies = _f.iter().map(|v| v.as_str().unwrap().to_owned()).collect();
         }
+        if let Some(_x) = val.get("after") {
+          mo.after = string_or_array(_x);
+        }
         if let Some(_x) = val.get("force") {
           mo.force = matches!(_x.as_str().unwrap(), "true");
         }
         if let Some(_x) = val.get("post") {
           mo.post = String::from(_x.as_str().unwrap());
         }
+        if let Some(_x) = val.get("script") {
+          mo.script = String::from(_x.as_str().unwrap());
+        }
+        mo.shell = match val.get("shell") {
+          Some(_x) => _x.clone().try_into().unwrap(),
+          None => config.shell.clone().unwrap_or_default(),
+        };
+        if let Some(_x) = val.get("cwd") {
+          mo.cwd = String::from(_x.as_str().unwrap());
+        }
+        if let Some(_x) = val.get("env") {
+          mo.env = _x
+            .as_table()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| {
+              // `JOBS = 4` is as good as `JOBS = '4'`
+              let v = match v {
+                value::Value::String(s) => s.to_owned(),
+                v => v.to_string(),
+              };
+              (k.to_owned(), v)
+            })
+            .collect();
+        }
         //
         // the `os =` entry in the config will be formatted either
-        // `windows` or `linux::<distro>`
+        // `windows` or `linux::<distro>`, maybe with a version on the end,
+        // or a list of those. `not_os =` is the same, but the other way around.
         if let Some(_x) = val.get("os") {
-          let _b = String::from(_x.as_str().unwrap());
-          let _a: Vec<&str> = _b.split("::").collect::<Vec<&str>>();
-          // TODO change this to match on strum's VariantNotFound
-          mo.os = if _a.len() > 1 {
-            Some(OS::Linux(
-              LinuxDistro::from_str(_a[1].to_lowercase().as_str()).unwrap(),
-            ))
-          } else {
-            Some(OS::from_str(_a[0].to_lowercase().as_str()).unwrap())
-          };
-        } else {
-          mo.os = None;
+          // deserialize_files() has already turned away anything that doesn't parse
+          mo.os = string_or_array(_x)
+            .iter()
+            .map(|o| OsFilter::from_str(o).unwrap())
+            .collect();
+        }
+        if let Some(_x) = val.get("not_os") {
+          mo.not_os = string_or_array(_x)
+            .iter()
+            .map(|o| OsFilter::from_str(o).unwrap())
+            .collect();
+        }
+        if let Some(_x) = val.get("arch") {
+          mo.arch = string_or_array(_x);
+        }
+        if let Some(_x) = val.get("hosts") {
+          mo.hosts = string_or_array(_x);
+        }
+        if let Some(_x) = val.get("when") {
+          mo.when = _x.as_str().unwrap().to_owned();
+        }
+        if let Some(_x) = val.get("tags") {
+          mo.tags = string_or_array(_x);
         }
         (mo.name.clone(), mo)
       })
@@ -294,6 +855,14 @@ impl Config {
   }
 }
 
+/// Lots of our fields can be either `'one'` or `['one', 'two']`.
+fn string_or_array(v: &value::Value) -> Vec<String> {
+  match v.as_array() {
+    Some(a) => a.iter().map(|x| x.as_str().unwrap().to_owned()).collect(),
+    None => vec![v.as_str().unwrap().to_owned()],
+  }
+}
+
 /// This takes our file/task array and turns them into `ManagedObjects`,
 /// to be stuffed into the `Config`.
 pub fn deserialize_files<'de, D>(deserializer: D) -> Result<Vec<(String, value::Value)>, D::Error>
@@ -303,6 +872,44 @@ where
   let mut files: Vec<(String, value::Value)> = Vec::new();
   let raw_files: Vec<value::Table> = Deserialize::deserialize(deserializer)?;
   for mut entry in raw_files {
+    // an environment variable is a string; there's nothing sensible to make of a list or table
+    if let Some(env) = entry.get("env").and_then(|e| e.as_table()) {
+      if let Some((k, _)) = env.iter().find(|(_, v)| v.is_array() || v.is_table()) {
+        return Err(serde::de::Error::custom(format!(
+          "`env` value for `{}` has to be a string, number or boolean",
+          k
+        )));
+      }
+    }
+    // toml can only point at where the list of objects starts, so say which one it is
+    let which = entry
+      .get("file")
+      .or_else(|| entry.get("task"))
+      .and_then(|n| n.as_str())
+      .unwrap_or_default()
+      .to_owned();
+    for key in ["os", "not_os"] {
+      let parses = |o: &value::Value| o.as_str().is_some_and(|o| OsFilter::from_str(o).is_ok());
+      match entry.get(key) {
+        Some(value::Value::Array(a)) if a.iter().all(parses) => {}
+        Some(v) if !v.is_array() && parses(v) => {}
+        None => {}
+        Some(v) => {
+          return Err(serde::de::Error::custom(format!(
+            "`{}` for {} has to be platforms like `linux::arch` or `windows`, not `{}`",
+            key, which, v
+          )))
+        }
+      }
+    }
+    if let Some(shell) = entry.get("shell") {
+      if shell.clone().try_into::<Shell>().is_err() {
+        return Err(serde::de::Error::custom(format!(
+          "`shell` for {} has to be a program (`'zsh'`) or a command line (`['zsh', '-c']`), not `{}`",
+          which, shell
+        )));
+      }
+    }
     if let Some(name) = entry.remove("file") {
       if let Some(name) = name.as_str() {
         files.push((name.to_owned(), value::Value::Table(entry)));
@@ -334,18 +941,23 @@ pub fn deserialize_file(file: &str) -> HMResult<Config> {
   };
 
   let mut file_contents = BufReader::new(g);
-  match file_contents.read_to_string(&mut contents) {
-    Ok(v) => v,
-    Err(_e) => 0,
-  };
+  let _ = file_contents.read_to_string(&mut contents);
   if cfg!(debug_assertions) {
-    println!("file: {}", &file);
+    eprintln!("file: {}", &file);
   }
   deserialize_str(&contents)
 }
 
 fn deserialize_str(contents: &str) -> HMResult<Config> {
-  toml::from_str(contents).map_err(|e| HMError::Other(e.to_string()))
+  toml::from_str(contents).map_err(|e: toml::de::Error| {
+    let line_number = e
+      .span()
+      .map(|s| contents[..s.start].matches('\n').count() + 1);
+    HMError::Regular(hmek::ConfigError {
+      line_number,
+      reason: e.message().to_string(),
+    })
+  })
 }
 
 /// Make sure $XDG_CONFIG_DIR exists.
@@ -422,4 +1034,307 @@ mod config_test {
     .unwrap();
     assert_eq!(vec!["grim", "slurp"], a.get_mo("zt").unwrap().dependencies);
   }
+
+  #[test]
+  fn os_lists() {
+    let mut a: Config = deserialize_str(
+      r#"
+      [[obj]]
+      task = 'apt'
+      solution = 'sudo apt update'
+      os = ['linux::debian', 'linux::ubuntu']
+
+      [[obj]]
+      task = 'rustup'
+      solution = 'rustup update'
+      not_os = 'windows'
+    "#,
+    )
+    .unwrap();
+    let apt = a.get_mo("apt").unwrap();
+    assert_eq!(2, apt.os.len());
+    assert_eq!(OS::Linux(LinuxDistro::Ubuntu), apt.os[1].os);
+    assert_eq!(OS::Windows, a.get_mo("rustup").unwrap().not_os[0].os);
+  }
+
+  #[test]
+  fn tags_and_profiles() {
+    let a: Config = deserialize_str(
+      r#"
+      [[obj]]
+      file = 'sway'
+      source = '~/dotfiles/sway'
+      destination = '~/.config/sway'
+      method = 'symlink'
+      tags = ['gui']
+
+      [[obj]]
+      task = 'fonts'
+      solution = 'fc-cache -f'
+      tags = 'gui'
+
+      [[obj]]
+      task = 'rustup'
+      solution = 'rustup update'
+      tags = ['dev']
+      dependencies = ['fonts']
+
+      [[obj]]
+      task = 'zt'
+      solution = 'git pull'
+
+      [profiles.server]
+      skip_tags = ['gui']
+    "#,
+    )
+    .unwrap();
+    let server = a.profiles["server"].clone();
+    let picked = |tags: &[String], skip: &[String]| {
+      let mut n: Vec<String> = select_by_tags(Config::as_managed_objects(a.clone()), tags, skip)
+        .into_keys()
+        .collect();
+      n.sort();
+      n
+    };
+    // rustup isn't gui, but needs fonts
+    assert_eq!(
+      vec!["fonts", "rustup", "zt"],
+      picked(&[], &server.skip_tags)
+    );
+    assert_eq!(vec!["fonts", "sway"], picked(&["gui".to_string()], &[]));
+    assert_eq!(vec!["fonts", "rustup"], picked(&["dev".to_string()], &[]));
+    assert_eq!(4, picked(&[], &[]).len());
+  }
+
+  #[test]
+  fn excluding() {
+    let mos: HashMap<String, ManagedObject> = [
+      ("zt", vec!["maim", "slop"]),
+      ("maim", vec!["maim_dependencies", "cmake"]),
+      ("slop", vec!["cmake"]),
+      ("screenshot", vec!["slop"]),
+      ("maim_dependencies", vec![]),
+      ("cmake", vec![]),
+      ("fonts", vec![]),
+    ]
+    .iter()
+    .map(|(n, d)| {
+      let mo = ManagedObject {
+        name: n.to_string(),
+        dependencies: d.iter().map(|s| s.to_string()).collect(),
+        ..Default::default()
+      };
+      (n.to_string(), mo)
+    })
+    .collect();
+    let left = exclude(mos, &["maim".to_string()]);
+    let mut names: Vec<&String> = left.keys().collect();
+    names.sort();
+    // zt can't go without maim. maim_dependencies stays - it's fine on its own
+    assert_eq!(
+      vec!["cmake", "fonts", "maim_dependencies", "screenshot", "slop"],
+      names
+    );
+    assert_eq!(vec!["cmake".to_string()], left["slop"].dependencies);
+  }
+
+  #[test]
+  fn unknown_os_is_a_config_error() {
+    match deserialize_str(
+      r#"
+      [[obj]]
+      task = 'zt'
+      solution = 'make install'
+
+      [[obj]]
+      task = 'beos_things'
+      solution = 'true'
+      os = ['linux::arch', 'linux::beos']
+    "#,
+    ) {
+      Err(HMError::Regular(hmek::ConfigError { reason, .. })) => {
+        assert!(reason.starts_with("`os` for beos_things"), "{}", reason)
+      }
+      Err(e) => panic!("expected a ConfigError, got {:?}", e),
+      Ok(_c) => panic!("beos isn't a platform we know"),
+    }
+  }
+
+  #[test]
+  fn hostname_globs() {
+    let laptop = Platform {
+      hostname: "Laptop-Work".to_string(),
+      ..Default::default()
+    };
+    let hosts = |h: &[&str]| h.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+    assert!(laptop.matches_host(&hosts(&["laptop-*", "build01"])));
+    assert!(!laptop.matches_host(&hosts(&["build0?"])));
+    assert!(laptop.matches_host(&[]));
+  }
+
+  #[test]
+  fn shell_falls_back_to_global() {
+    let a: Config = deserialize_str(
+      r#"
+      shell = 'sh'
+
+      [[obj]]
+      task = 'fonts'
+      script = '~/dotfiles/fonts.py'
+      shell = 'python3'
+
+      [[obj]]
+      task = 'zt'
+      solution = 'git pull'
+
+      [[obj]]
+      task = 'nix'
+      solution = 'make'
+      shell = ['nix-shell', '--run']
+    "#,
+    )
+    .unwrap();
+    let mos = Config::as_managed_objects(a);
+    assert_eq!(
+      vec!["python3", "~/dotfiles/fonts.py"],
+      mos["fonts"].shell.argv(&mos["fonts"].script, true)
+    );
+    assert_eq!(
+      vec!["sh", "-c", "git pull"],
+      mos["zt"].shell.argv("git pull", false)
+    );
+    assert_eq!(
+      vec!["nix-shell", "--run", "make"],
+      mos["nix"].shell.argv("make", false)
+    );
+  }
+
+  #[test]
+  fn os_release_ids() {
+    let f = |s: &str| OsFilter::from_str(s).unwrap();
+    let pop = Platform::from_os_release("pop", "ubuntu debian", "22.04");
+    assert_eq!(OS::Linux(LinuxDistro::Ubuntu), pop.os);
+    assert!(pop.matches(&f("linux::debian")));
+    assert!(pop.matches(&f("linux")));
+    assert!(!pop.matches(&f("linux::fedora")));
+    let rocky = Platform::from_os_release("rocky", "rhel centos fedora", "9.3");
+    assert_eq!(OS::Linux(LinuxDistro::Rhel), rocky.os);
+    assert!(rocky.matches(&f("linux::fedora")));
+    let tumbleweed = Platform::from_os_release("opensuse-tumbleweed", "opensuse suse", "");
+    assert_eq!(OS::Linux(LinuxDistro::OpenSuse), tumbleweed.os);
+    let mystery = Platform::from_os_release("mystery", "", "");
+    assert_eq!(OS::Linux(LinuxDistro::Generic), mystery.os);
+    assert!(!mystery.matches(&f("windows")));
+  }
+
+  #[test]
+  fn os_override() {
+    let f = |s: &str| OsFilter::from_str(s).unwrap();
+    let pop = Platform::from_os_release("pop", "ubuntu debian", "22.04");
+    let arch = pop.clone().pretending_to_be("linux::arch").unwrap();
+    assert_eq!(OS::Linux(LinuxDistro::Arch), arch.os);
+    assert!(!arch.matches(&f("linux::debian")));
+    let focal = pop
+      .clone()
+      .pretending_to_be("linux::ubuntu::20.04")
+      .unwrap();
+    assert!(focal.matches(&f("linux::ubuntu<22.04")));
+    // a real ubuntu would say it's like debian
+    assert!(focal.matches(&f("linux::debian")));
+    assert!(!focal.matches(&f("linux::debian>=11")));
+    let rocky = pop.clone().pretending_to_be("linux::rhel").unwrap();
+    assert!(rocky.matches(&f("linux::fedora")));
+    assert!(pop.pretending_to_be("linux::beos").is_err());
+  }
+
+  #[test]
+  fn os_versions() {
+    let f = |s: &str| OsFilter::from_str(s).unwrap();
+    let jammy = Platform::from_os_release("ubuntu", "debian", "22.04");
+    assert!(jammy.matches(&f("linux::ubuntu>=22.04")));
+    assert!(jammy.matches(&f("linux::ubuntu>20.04")));
+    assert!(!jammy.matches(&f("linux::ubuntu<22.04")));
+    assert!(jammy.matches(&f("linux::ubuntu::22")));
+    assert!(!jammy.matches(&f("linux::ubuntu::24.04")));
+    // leading zeroes don't count, however it's compared
+    assert!(jammy.matches(&f("linux::ubuntu=22.4")));
+    assert!(jammy.matches(&f("linux::ubuntu::22.4")));
+    assert!(jammy.matches(&f("linux::ubuntu>=22.4")));
+    assert!(jammy.matches(&f("linux::ubuntu<=22.4")));
+    // ubuntu is like debian, but its version numbers aren't debian's
+    assert!(jammy.matches(&f("linux::debian")));
+    assert!(!jammy.matches(&f("linux::debian>=11")));
+    assert!(!jammy.matches(&f("linux::debian::22")));
+    let fedora = Platform::from_os_release("fedora", "", "39");
+    assert!(fedora.matches(&f("linux::fedora::39")));
+    assert!(fedora.matches(&f("linux::fedora<=40")));
+    assert!(!Platform::from_os_release("fedora", "", "").matches(&f("linux::fedora::39")));
+    assert_eq!(
+      "linux::ubuntu>=22.04",
+      f("linux::ubuntu>=22.04").to_string()
+    );
+    let arm = Platform {
+      arch: "aarch64".to_string(),
+      ..Default::default()
+    };
+    assert!(arm.matches_arch(&["arm64".to_string()]));
+    assert!(!arm.matches_arch(&["x86_64".to_string()]));
+    assert!(arm.matches_arch(&[]));
+    // std::env::consts::ARCH says `arm` on a Raspberry Pi
+    let pi = Platform {
+      arch: "arm".to_string(),
+      ..Default::default()
+    };
+    assert!(pi.matches_arch(&["aarch64".to_string(), "armv7".to_string()]));
+    assert!(pi.matches_arch(&["armhf".to_string()]));
+    assert!(!pi.matches_arch(&["aarch64".to_string()]));
+  }
+
+  #[test]
+  fn cwd_and_env() {
+    let mut a: Config = deserialize_str(
+      r#"
+      [[obj]]
+      task = 'zt'
+      solution = 'make install'
+      cwd = '~/dotfiles/zt'
+      env = { CC = 'clang', PREFIX = '~/.local' }
+    "#,
+    )
+    .unwrap();
+    let mo = a.get_mo("zt").unwrap();
+    assert_eq!("~/dotfiles/zt", mo.cwd);
+    assert_eq!("clang", mo.env["CC"]);
+    assert_eq!("~/.local", mo.env["PREFIX"]);
+    let mut a: Config = deserialize_str(
+      r#"
+      [[obj]]
+      task = 'zt'
+      solution = 'make install'
+      env = { JOBS = 4, VERBOSE = true }
+    "#,
+    )
+    .unwrap();
+    let mo = a.get_mo("zt").unwrap();
+    assert_eq!("4", mo.env["JOBS"]);
+    assert_eq!("true", mo.env["VERBOSE"]);
+    assert!(deserialize_str(
+      r#"
+      [[obj]]
+      task = 'zt'
+      solution = 'make install'
+      env = { PATH = ['/usr/bin'] }
+    "#,
+    )
+    .is_err());
+    assert!(deserialize_str(
+      r#"
+      [[obj]]
+      task = 'zt'
+      solution = 'make install'
+      shell = 42
+    "#,
+    )
+    .is_err());
+  }
 }
@@ -0,0 +1,239 @@
+//! Our own dependency graph: register what depends on what, then ask for everything
+//! a node needs, in an order that satisfies all of it.
+//!
+//! If there's a cycle we say exactly where it is - `a -> b -> c -> a` -
+//! rather than handing back the whole graph and leaving you to find it.
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+///
+/// What can go wrong asking a `DepGraph` for dependencies.
+/// * NoSuchNode: we've never heard of it.
+/// * Cycle: the path around the loop, starting and ending on the same node.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DepGraphError {
+  NoSuchNode(String),
+  Cycle(Vec<String>),
+}
+
+impl fmt::Display for DepGraphError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      DepGraphError::NoSuchNode(n) => write!(f, "no such node `{}`", n),
+      DepGraphError::Cycle(c) => write!(f, "{}", c.join(" -> ")),
+    }
+  }
+}
+
+///
+/// Nodes (by name) and the nodes each depends on, in the order they were given.
+///
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DepGraph {
+  nodes: Vec<String>,
+  index: HashMap<String, usize>,
+  dependencies: Vec<Vec<usize>>,
+}
+
+impl DepGraph {
+  pub fn new() -> DepGraph {
+    DepGraph::default()
+  }
+
+  fn node(&mut self, name: &str) -> usize {
+    match self.index.get(name) {
+      Some(i) => *i,
+      None => {
+        self.nodes.push(name.to_string());
+        self.dependencies.push(Vec::new());
+        self.index.insert(name.to_string(), self.nodes.len() - 1);
+        self.nodes.len() - 1
+      }
+    }
+  }
+
+  /// Note that `node` depends on each of `dependencies`. Both end up in the graph,
+  /// whether or not anyone's registered the dependencies themselves.
+  pub fn register_dependencies(&mut self, node: &str, dependencies: &[String]) {
+    let n = self.node(node);
+    for d in dependencies {
+      let d = self.node(d);
+      if !self.dependencies[n].contains(&d) {
+        self.dependencies[n].push(d);
+      }
+    }
+  }
+
+  /// Every node, in the order we first heard of them.
+  pub fn nodes(&self) -> &[String] {
+    &self.nodes
+  }
+
+  /// Everything `target` needs, all the way down, ending with `target` itself -
+  /// each node only after everything it depends on.
+  pub fn dependencies_of(&self, target: &str) -> Result<Vec<String>, DepGraphError> {
+    let t = self.index_of(target)?;
+    let mut done: Vec<bool> = vec![false; self.nodes.len()];
+    let mut path: Vec<usize> = Vec::new();
+    let mut order: Vec<String> = Vec::new();
+    self.visit(t, &mut done, &mut path, &mut order)?;
+    Ok(order)
+  }
+
+  /// Everything that needs `target`, directly or through something else - nearest first.
+  pub fn dependents_of(&self, target: &str) -> Result<Vec<String>, DepGraphError> {
+    let t = self.index_of(target)?;
+    let mut seen: Vec<bool> = vec![false; self.nodes.len()];
+    seen[t] = true;
+    let mut q: VecDeque<usize> = VecDeque::from([t]);
+    let mut found: Vec<String> = Vec::new();
+    while let Some(n) = q.pop_front() {
+      for (i, d) in self.dependencies.iter().enumerate() {
+        if !seen[i] && d.contains(&n) {
+          seen[i] = true;
+          found.push(self.nodes[i].clone());
+          q.push_back(i);
+        }
+      }
+    }
+    Ok(found)
+  }
+
+  /// Everything `target` needs, all the way down, in the order we first heard of them.
+  /// Unlike `dependencies_of` this doesn't mind a cycle - `target` is only in there if it's in one.
+  pub fn reachable_from(&self, target: &str) -> Result<Vec<String>, DepGraphError> {
+    let r = self.reach(self.index_of(target)?);
+    Ok(
+      self
+        .nodes
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| r[*i])
+        .map(|(_, n)| n.clone())
+        .collect(),
+    )
+  }
+
+  /// Every cycle: each group of nodes that all need one another (or a node that needs
+  /// itself), in the order we first heard of them.
+  pub fn cycles(&self) -> Vec<Vec<String>> {
+    let reach: Vec<Vec<bool>> = (0..self.nodes.len()).map(|i| self.reach(i)).collect();
+    let mut placed: Vec<bool> = vec![false; self.nodes.len()];
+    let mut found: Vec<Vec<String>> = Vec::new();
+    for i in 0..self.nodes.len() {
+      if placed[i] || !reach[i][i] {
+        continue;
+      }
+      let group: Vec<usize> = (0..self.nodes.len())
+        .filter(|j| reach[i][*j] && reach[*j][i])
+        .collect();
+      group.iter().for_each(|j| placed[*j] = true);
+      found.push(group.iter().map(|j| self.nodes[*j].clone()).collect());
+    }
+    found
+  }
+
+  fn index_of(&self, name: &str) -> Result<usize, DepGraphError> {
+    self
+      .index
+      .get(name)
+      .copied()
+      .ok_or_else(|| DepGraphError::NoSuchNode(name.to_string()))
+  }
+
+  /// Which nodes `from` leads to, by index - `from` included only if it leads back to itself.
+  fn reach(&self, from: usize) -> Vec<bool> {
+    let mut seen: Vec<bool> = vec![false; self.nodes.len()];
+    let mut q: Vec<usize> = self.dependencies[from].clone();
+    while let Some(n) = q.pop() {
+      if !seen[n] {
+        seen[n] = true;
+        q.extend(self.dependencies[n].iter());
+      }
+    }
+    seen
+  }
+
+  fn visit(
+    &self,
+    n: usize,
+    done: &mut Vec<bool>,
+    path: &mut Vec<usize>,
+    order: &mut Vec<String>,
+  ) -> Result<(), DepGraphError> {
+    if done[n] {
+      return Ok(());
+    }
+    if let Some(start) = path.iter().position(|p| *p == n) {
+      // we've come back around to somewhere we already are
+      let mut cycle: Vec<String> = path[start..]
+        .iter()
+        .map(|p| self.nodes[*p].clone())
+        .collect();
+      cycle.push(self.nodes[n].clone());
+      return Err(DepGraphError::Cycle(cycle));
+    }
+    path.push(n);
+    for d in &self.dependencies[n] {
+      self.visit(*d, done, path, order)?;
+    }
+    path.pop();
+    done[n] = true;
+    order.push(self.nodes[n].clone());
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod depgraph_test {
+  use super::*;
+
+  fn deps(d: &[&str]) -> Vec<String> {
+    d.iter().map(|s| s.to_string()).collect()
+  }
+
+  #[test]
+  fn dependencies_come_first() {
+    let mut g = DepGraph::new();
+    g.register_dependencies("zt", &deps(&["maim", "slop"]));
+    g.register_dependencies("maim", &deps(&["maim_dependencies"]));
+    g.register_dependencies("slop", &deps(&["maim_dependencies"]));
+    assert_eq!(
+      deps(&["maim_dependencies", "maim", "slop", "zt"]),
+      g.dependencies_of("zt").unwrap()
+    );
+    assert_eq!(
+      deps(&["maim_dependencies"]),
+      g.dependencies_of("maim_dependencies").unwrap()
+    );
+    assert_eq!(
+      Err(DepGraphError::NoSuchNode(String::from("nope"))),
+      g.dependencies_of("nope")
+    );
+    assert_eq!(
+      deps(&["maim", "slop", "zt"]),
+      g.dependents_of("maim_dependencies").unwrap()
+    );
+    assert!(g.dependents_of("zt").unwrap().is_empty());
+  }
+
+  #[test]
+  fn cycles_name_the_loop() {
+    let mut g = DepGraph::new();
+    g.register_dependencies("zt", &deps(&["a"]));
+    g.register_dependencies("a", &deps(&["b"]));
+    g.register_dependencies("b", &deps(&["c"]));
+    g.register_dependencies("c", &deps(&["a"]));
+    let e = g.dependencies_of("zt").unwrap_err();
+    assert_eq!(DepGraphError::Cycle(deps(&["a", "b", "c", "a"])), e);
+    assert_eq!("a -> b -> c -> a", e.to_string());
+    g.register_dependencies("me", &deps(&["me"]));
+    assert_eq!(
+      Err(DepGraphError::Cycle(deps(&["me", "me"]))),
+      g.dependencies_of("me")
+    );
+    assert_eq!(vec![deps(&["a", "b", "c"]), deps(&["me"])], g.cycles());
+    assert_eq!(deps(&["a", "b", "c"]), g.reachable_from("zt").unwrap());
+  }
+}
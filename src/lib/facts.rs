@@ -0,0 +1,129 @@
+//! Everything `hm` knows about the machine it's running on - what `os =`, `arch =`,
+//! `hosts =` and `when =` get matched against, and a few more things besides.
+//! `hm facts` prints these (`hm facts --json` for something a script can read),
+//! which is the first place to look when an object gets filtered out and you didn't expect it to.
+extern crate serde;
+
+use serde::Serialize;
+use std::{env, fmt, path::PathBuf};
+
+use crate::condition::which;
+use crate::config::Platform;
+use crate::state;
+
+/// Package managers we look for on `$PATH`.
+const PACKAGE_MANAGERS: [&str; 16] = [
+  "apt",
+  "dnf",
+  "yum",
+  "pacman",
+  "zypper",
+  "apk",
+  "emerge",
+  "xbps-install",
+  "nix",
+  "brew",
+  "flatpak",
+  "snap",
+  "cargo",
+  "winget",
+  "choco",
+  "scoop",
+];
+
+///
+/// What we've worked out about this machine.
+///
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Facts {
+  /// As you'd write it in `os =`: `linux::ubuntu`, `windows`...
+  pub os: String,
+  /// Distros from `ID_LIKE` - `os =` filters for these match too.
+  pub like: Vec<String>,
+  pub version: String,
+  pub arch: String,
+  pub hostname: String,
+  pub user: String,
+  pub home: String,
+  pub config_dir: String,
+  pub data_dir: String,
+  pub state_dir: String,
+  pub cache_dir: String,
+  pub package_managers: Vec<String>,
+  pub shell: String,
+}
+
+impl Facts {
+  /// Look around.
+  pub fn detect() -> Facts {
+    Facts::from_platform(&Platform::detect())
+  }
+
+  /// Everything we know, given the `Platform` we're on.
+  pub fn from_platform(platform: &Platform) -> Facts {
+    let dir = |d: Option<PathBuf>| {
+      d.map(|p| p.to_string_lossy().to_string())
+        .unwrap_or_default()
+    };
+    Facts {
+      os: platform.os.to_string(),
+      like: platform.like.iter().map(|l| l.to_string()).collect(),
+      version: platform.version.clone(),
+      arch: platform.arch.clone(),
+      hostname: platform.hostname.clone(),
+      user: current_user(),
+      home: dir(dirs_next::home_dir()),
+      config_dir: dir(dirs_next::config_dir()),
+      data_dir: dir(dirs_next::data_dir()),
+      state_dir: dir(state_dir()),
+      cache_dir: dir(dirs_next::cache_dir()),
+      package_managers: PACKAGE_MANAGERS
+        .iter()
+        .filter(|p| which(p).is_some())
+        .map(|p| p.to_string())
+        .collect(),
+      shell: env::var("SHELL")
+        .or_else(|_e| env::var("ComSpec"))
+        .unwrap_or_default(),
+    }
+  }
+}
+
+/// One fact per line, names lined up.
+impl fmt::Display for Facts {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let rows = [
+      ("os", self.os.clone()),
+      ("like", self.like.join(", ")),
+      ("version", self.version.clone()),
+      ("arch", self.arch.clone()),
+      ("hostname", self.hostname.clone()),
+      ("user", self.user.clone()),
+      ("home", self.home.clone()),
+      ("config_dir", self.config_dir.clone()),
+      ("data_dir", self.data_dir.clone()),
+      ("state_dir", self.state_dir.clone()),
+      ("cache_dir", self.cache_dir.clone()),
+      ("package_managers", self.package_managers.join(", ")),
+      ("shell", self.shell.clone()),
+    ];
+    for (k, v) in rows.iter() {
+      writeln!(f, "{:<18}{}", k, v)?;
+    }
+    Ok(())
+  }
+}
+
+/// Who we're running as, going by `$USER` (or `%USERNAME%`).
+pub fn current_user() -> String {
+  env::var("USER")
+    .or_else(|_e| env::var("USERNAME"))
+    .unwrap_or_default()
+}
+
+/// Whichever of `$XDG_STATE_HOME` or `~/.local/state` our state file went in.
+fn state_dir() -> Option<PathBuf> {
+  // `<state dir>/homemaker/state.toml`
+  let f = state::state_file().ok()?;
+  f.parent()?.parent().map(|d| d.to_path_buf())
+}
@@ -0,0 +1,370 @@
+//! Draw the dependency graph `get_task_batches()` works from, for `hm graph`:
+//! as plain text, Graphviz DOT (`hm graph --format dot | dot -Tpng > deps.png`),
+//! or a Mermaid flowchart to paste into a README.
+//!
+//! Edges point from an object to what it depends on. Objects that don't apply to
+//! this machine (`os`, `arch`, `hosts`, `when`) are greyed out, dependencies nothing
+//! defines are red, and anything caught up in a cycle is orange.
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+
+use crate::applies_to;
+use crate::config::{ManagedObject, Platform};
+use crate::depgraph::DepGraph;
+use crate::hmerror::{ErrorKind as hmek, HMError};
+
+///
+/// How a node in the graph stands.
+/// * Applies: it's defined, and it's for this machine.
+/// * Filtered: it's defined, but not for this machine.
+/// * Undefined: something depends on it, but nothing says what it is.
+///
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeState {
+  Applies,
+  Filtered,
+  Undefined,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Node {
+  pub name: String,
+  pub is_task: bool,
+  pub state: NodeState,
+  pub in_cycle: bool,
+}
+
+///
+/// Our objects and their dependencies, ready to draw.
+/// `edges` are `(dependent, dependency)` indexes into `nodes`, and `after` the same
+/// for `after =` - ordering only, drawn dashed.
+///
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Graph {
+  pub nodes: Vec<Node>,
+  pub edges: Vec<(usize, usize)>,
+  pub after: Vec<(usize, usize)>,
+}
+
+impl Graph {
+  /// The graph of `mos` as seen from `platform` - just the parts under `targets`, if there are any.
+  /// A target that isn't in the config is a `DependencyUndefinedError`, same as `get_task_batches()`.
+  pub fn new(
+    mos: &HashMap<String, ManagedObject>,
+    targets: &HashSet<String>,
+    platform: &Platform,
+  ) -> Result<Graph, HMError> {
+    let mut unknown: Vec<&String> = targets.iter().filter(|t| !mos.contains_key(*t)).collect();
+    unknown.sort();
+    if let Some(t) = unknown.first() {
+      return Err(HMError::Regular(hmek::DependencyUndefinedError {
+        dependency: t.to_string(),
+      }));
+    }
+    let dg = depgraph_of(mos);
+    // dependencies nobody defined are in there too
+    let mut names: Vec<&String> = if targets.is_empty() {
+      dg.nodes().iter().collect()
+    } else {
+      // everything the targets need, all the way down
+      let needed: HashSet<String> = targets
+        .iter()
+        .flat_map(|t| dg.reachable_from(t).unwrap_or_default())
+        .collect();
+      dg.nodes()
+        .iter()
+        .filter(|n| targets.contains(*n) || needed.contains(*n))
+        .collect()
+    };
+    // config.toml order, then whatever's undefined
+    names.sort_by_key(|n| (mos.get(*n).map_or(usize::MAX, |m| m.order), *n));
+    let index: HashMap<&String, usize> = names.iter().enumerate().map(|(i, n)| (*n, i)).collect();
+    let mut g = Graph::default();
+    for n in &names {
+      g.nodes.push(match mos.get(*n) {
+        Some(m) => Node {
+          name: m.name.clone(),
+          is_task: m.is_task(),
+          state: match applies_to(m, platform) {
+            Ok(()) => NodeState::Applies,
+            Err(_e) => NodeState::Filtered,
+          },
+          in_cycle: false,
+        },
+        None => Node {
+          name: n.to_string(),
+          is_task: true,
+          state: NodeState::Undefined,
+          in_cycle: false,
+        },
+      });
+      if let Some(m) = mos.get(*n) {
+        for d in &m.dependencies {
+          g.edges.push((index[n], index[d]));
+        }
+        // only worth drawing if it's in the picture anyway
+        for a in m.after.iter().filter(|a| index.contains_key(a)) {
+          g.after.push((index[n], index[a]));
+        }
+      }
+    }
+    for c in g.cycles() {
+      for i in c {
+        g.nodes[i].in_cycle = true;
+      }
+    }
+    Ok(g)
+  }
+
+  /// Groups of nodes that depend on each other, one way or another.
+  pub fn cycles(&self) -> Vec<Vec<usize>> {
+    let mut dg = DepGraph::new();
+    for (i, n) in self.nodes.iter().enumerate() {
+      let deps: Vec<String> = self
+        .edges
+        .iter()
+        .filter(|(a, _)| *a == i)
+        .map(|(_, b)| self.nodes[*b].name.clone())
+        .collect();
+      dg.register_dependencies(&n.name, &deps);
+    }
+    let index: HashMap<&String, usize> = self
+      .nodes
+      .iter()
+      .enumerate()
+      .map(|(i, n)| (&n.name, i))
+      .collect();
+    dg.cycles()
+      .iter()
+      .map(|c| c.iter().map(|n| index[n]).collect())
+      .collect()
+  }
+
+  fn edge_in_cycle(cycles: &[Vec<usize>], (a, b): (usize, usize)) -> bool {
+    a == b || cycles.iter().any(|c| c.contains(&a) && c.contains(&b))
+  }
+
+  /// `name -> dependency, dependency`, a line per node.
+  pub fn to_text(&self) -> String {
+    let mut s = String::new();
+    for (i, n) in self.nodes.iter().enumerate() {
+      s.push_str(&n.name);
+      let deps: Vec<&str> = self
+        .edges
+        .iter()
+        .filter(|(a, _)| *a == i)
+        .map(|(_, b)| self.nodes[*b].name.as_str())
+        .collect();
+      if !deps.is_empty() {
+        s.push_str(&format!(" -> {}", deps.join(", ")));
+      }
+      let after: Vec<&str> = self
+        .after
+        .iter()
+        .filter(|(a, _)| *a == i)
+        .map(|(_, b)| self.nodes[*b].name.as_str())
+        .collect();
+      if !after.is_empty() {
+        s.push_str(&format!(" (after {})", after.join(", ")));
+      }
+      let notes: Vec<&str> = [
+        (n.state == NodeState::Filtered, "not here"),
+        (n.state == NodeState::Undefined, "undefined"),
+        (n.in_cycle, "cycle"),
+      ]
+      .iter()
+      .filter(|(b, _)| *b)
+      .map(|(_, t)| *t)
+      .collect();
+      if !notes.is_empty() {
+        s.push_str(&format!(" ({})", notes.join(", ")));
+      }
+      s.push('\n');
+    }
+    s
+  }
+
+  /// The nodes, and the edges between them by name, for scripts.
+  pub fn to_json(&self) -> Value {
+    let edge = |(a, b): &(usize, usize)| {
+      json!({
+        "from": self.nodes[*a].name,
+        "to": self.nodes[*b].name,
+      })
+    };
+    json!({
+      "nodes": self.nodes,
+      "edges": self.edges.iter().map(edge).collect::<Vec<Value>>(),
+      "after": self.after.iter().map(edge).collect::<Vec<Value>>(),
+      "cycles": self
+        .cycles()
+        .iter()
+        .map(|c| c.iter().map(|i| self.nodes[*i].name.clone()).collect())
+        .collect::<Vec<Vec<String>>>(),
+    })
+  }
+
+  /// Graphviz DOT.
+  pub fn to_dot(&self) -> String {
+    let q = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+    let mut s = String::from("digraph hm {\n  node [shape=box, style=rounded];\n");
+    for n in &self.nodes {
+      let mut attrs: Vec<String> = Vec::new();
+      if !n.is_task {
+        attrs.push(String::from("shape=note"));
+      }
+      match n.state {
+        NodeState::Applies => {}
+        NodeState::Filtered => attrs.push(String::from(
+          "style=\"rounded,dashed\", color=gray, fontcolor=gray",
+        )),
+        NodeState::Undefined => attrs.push(format!(
+          "style=\"rounded,dashed\", color=red, fontcolor=red, label={}",
+          q(&format!("{} (undefined)", n.name))
+        )),
+      }
+      if n.in_cycle {
+        attrs.push(String::from("color=orange, penwidth=2"));
+      }
+      if attrs.is_empty() {
+        s.push_str(&format!("  {};\n", q(&n.name)));
+      } else {
+        s.push_str(&format!("  {} [{}];\n", q(&n.name), attrs.join(", ")));
+      }
+    }
+    let cycles = self.cycles();
+    for (a, b) in &self.edges {
+      let style = if Graph::edge_in_cycle(&cycles, (*a, *b)) {
+        " [color=orange, penwidth=2]"
+      } else {
+        ""
+      };
+      s.push_str(&format!(
+        "  {} -> {}{};\n",
+        q(&self.nodes[*a].name),
+        q(&self.nodes[*b].name),
+        style
+      ));
+    }
+    for (a, b) in &self.after {
+      s.push_str(&format!(
+        "  {} -> {} [style=dashed];\n",
+        q(&self.nodes[*a].name),
+        q(&self.nodes[*b].name)
+      ));
+    }
+    s.push_str("}\n");
+    s
+  }
+
+  /// A Mermaid flowchart.
+  pub fn to_mermaid(&self) -> String {
+    let label = |s: &str| format!("\"{}\"", s.replace('"', "#quot;"));
+    let mut s = String::from("flowchart TD\n");
+    for (i, n) in self.nodes.iter().enumerate() {
+      let (open, close) = if n.is_task { ("(", ")") } else { ("[", "]") };
+      let text = match n.state {
+        NodeState::Undefined => format!("{} (undefined)", n.name),
+        _ => n.name.clone(),
+      };
+      s.push_str(&format!("  n{}{}{}{}", i, open, label(&text), close));
+      match (n.state, n.in_cycle) {
+        (_, true) => s.push_str(":::cycle"),
+        (NodeState::Filtered, _) => s.push_str(":::filtered"),
+        (NodeState::Undefined, _) => s.push_str(":::undefined"),
+        _ => {}
+      }
+      s.push('\n');
+    }
+    let cycles = self.cycles();
+    let mut cycle_links: Vec<String> = Vec::new();
+    for (i, (a, b)) in self.edges.iter().enumerate() {
+      s.push_str(&format!("  n{} --> n{}\n", a, b));
+      if Graph::edge_in_cycle(&cycles, (*a, *b)) {
+        cycle_links.push(i.to_string());
+      }
+    }
+    for (a, b) in &self.after {
+      s.push_str(&format!("  n{} -.-> n{}\n", a, b));
+    }
+    s.push_str("  classDef filtered stroke-dasharray: 5 5,color:#888,stroke:#888\n");
+    s.push_str("  classDef undefined stroke-dasharray: 5 5,color:#d00,stroke:#d00\n");
+    s.push_str("  classDef cycle stroke:#e80,stroke-width:2px\n");
+    if !cycle_links.is_empty() {
+      s.push_str(&format!(
+        "  linkStyle {} stroke:#e80,stroke-width:2px\n",
+        cycle_links.join(",")
+      ));
+    }
+    s
+  }
+}
+
+/// Every object and its `dependencies`, in `config.toml` order - whichever platform they're for.
+pub fn depgraph_of(mos: &HashMap<String, ManagedObject>) -> DepGraph {
+  let mut sorted: Vec<&ManagedObject> = mos.values().collect();
+  sorted.sort_by_key(|m| m.order);
+  let mut g = DepGraph::new();
+  for mo in sorted {
+    g.register_dependencies(&mo.name, &mo.dependencies);
+  }
+  g
+}
+
+#[cfg(test)]
+mod graph_test {
+  use super::*;
+
+  fn mo(name: &str, deps: &[&str]) -> (String, ManagedObject) {
+    (
+      name.to_string(),
+      ManagedObject {
+        name: name.to_string(),
+        solution: String::from("true"),
+        dependencies: deps.iter().map(|d| d.to_string()).collect(),
+        ..Default::default()
+      },
+    )
+  }
+
+  #[test]
+  fn cycles_and_undefined() {
+    let mos: HashMap<String, ManagedObject> = vec![
+      mo("zt", &["maim", "slop"]),
+      mo("maim", &["slop"]),
+      mo("slop", &["maim"]),
+      mo("fonts", &["nope"]),
+    ]
+    .into_iter()
+    .collect();
+    let g = Graph::new(&mos, &HashSet::new(), &Platform::default()).unwrap();
+    let node = |n: &str| g.nodes.iter().find(|x| x.name == n).unwrap();
+    assert!(node("maim").in_cycle && node("slop").in_cycle);
+    assert!(!node("zt").in_cycle);
+    assert_eq!(NodeState::Undefined, node("nope").state);
+    assert!(g
+      .to_dot()
+      .contains("\"maim\" -> \"slop\" [color=orange, penwidth=2];"));
+    assert!(g.to_dot().contains("\"zt\" -> \"maim\";"));
+    assert_eq!(json!(["maim", "slop"]), g.to_json()["cycles"][0]);
+    // only what zt needs
+    let g = Graph::new(
+      &mos,
+      &HashSet::from(["zt".to_string()]),
+      &Platform::default(),
+    )
+    .unwrap();
+    assert_eq!(3, g.nodes.len());
+    // asking after something that isn't there is a mistake, not an empty graph
+    assert!(matches!(
+      Graph::new(
+        &mos,
+        &HashSet::from(["nope".to_string()]),
+        &Platform::default()
+      ),
+      Err(HMError::Regular(hmek::DependencyUndefinedError { .. }))
+    ));
+  }
+}
@@ -3,6 +3,12 @@
 //! ```
 //! pub enum ErrorKind {
 //!   DependencyUndefinedError,
+//!   IncorrectPlatformError,
+//!   ExcludedPlatformError,
+//!   IncorrectArchError,
+//!   IncorrectHostError,
+//!   ConditionNotMetError,
+//!   InvalidConditionError,
 //!   CyclicalDependencyError,
 //!   SolutionError,
 //!   ConfigError,
@@ -10,13 +16,20 @@
 //! }
 //! ```
 //! * DependencyUndefinedError: A stated dependency doesn't have an object telling us how to satisfy it.
-//! * CyclicalDependencyError: a -> b and b -> a and neither is satisfied. The offending object is the tippy-top of the chain.
+//! * IncorrectPlatformError: A stated dependency exists, but its `os =` says it isn't for this machine.
+//! * ExcludedPlatformError: A stated dependency exists, but its `not_os =` says it isn't for this machine.
+//! * IncorrectArchError: A stated dependency exists, but its `arch =` says it isn't for this machine.
+//! * IncorrectHostError: A stated dependency exists, but its `hosts =` don't include this machine.
+//! * ConditionNotMetError: A stated dependency exists, but its `when =` doesn't hold on this machine.
+//! * InvalidConditionError: An object's `when =` isn't an expression we understand.
+//! * CyclicalDependencyError: a -> b and b -> a and neither is satisfied. `cycle` is the loop itself: `a -> b -> a`.
 //! * SolutionError: Something went wrong in our script.
-//! * ConfigError: Something is wrong with how you wrote the `config.toml`.
+//! * ConfigError: Something is wrong with how you wrote the `config.toml`, around `line_number` if we can say.
 //! * Other: Other.
 extern crate console;
 extern crate serde;
 use console::style;
+use serde::Serialize;
 use std::fmt;
 use std::io;
 
@@ -29,7 +42,9 @@ pub enum HMError {
   Other(String),
 }
 
-#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+/// Serializes as `{"kind": "DependencyUndefinedError", "dependency": "..."}` and so on.
+#[derive(Serialize, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[serde(tag = "kind")]
 pub enum ErrorKind {
   DependencyUndefinedError {
     dependency: String,
@@ -37,16 +52,41 @@ pub enum ErrorKind {
   IncorrectPlatformError {
     dependency: String,
     platform: config::OS,
-    target_platform: config::OS,
+    target_platform: Vec<config::OsFilter>,
+  },
+  ExcludedPlatformError {
+    dependency: String,
+    platform: config::OS,
+    excluded_platform: config::OsFilter,
+  },
+  IncorrectArchError {
+    dependency: String,
+    arch: String,
+    target_arch: Vec<String>,
+  },
+  IncorrectHostError {
+    dependency: String,
+    hostname: String,
+    target_hosts: Vec<String>,
+  },
+  ConditionNotMetError {
+    dependency: String,
+    condition: String,
+  },
+  InvalidConditionError {
+    dependency: String,
+    condition: String,
+    reason: String,
   },
   CyclicalDependencyError {
-    dependency_graph: String,
+    cycle: String,
   },
   SolutionError {
     solution: String,
   },
   ConfigError {
-    line_number: usize,
+    line_number: Option<usize>,
+    reason: String,
   },
   Other,
 }
@@ -68,7 +108,10 @@ impl From<Deserializer::Error> for HMError {
 impl ErrorKind {
   fn as_str(&self) -> &str {
     match *self {
-      ErrorKind::ConfigError { line_number: _ } => "configuration error",
+      ErrorKind::ConfigError {
+        line_number: _,
+        reason: _,
+      } => "configuration error",
       ErrorKind::SolutionError { solution: _ } => "solution error",
       ErrorKind::DependencyUndefinedError { dependency: _ } => "dependency undefined",
       ErrorKind::IncorrectPlatformError {
@@ -76,9 +119,31 @@ impl ErrorKind {
         platform: _,
         target_platform: _,
       } => "incorrect platform for dependency",
-      ErrorKind::CyclicalDependencyError {
-        dependency_graph: _,
-      } => "cyclical dependency",
+      ErrorKind::ExcludedPlatformError {
+        dependency: _,
+        platform: _,
+        excluded_platform: _,
+      } => "dependency excluded on this platform",
+      ErrorKind::IncorrectArchError {
+        dependency: _,
+        arch: _,
+        target_arch: _,
+      } => "incorrect architecture for dependency",
+      ErrorKind::IncorrectHostError {
+        dependency: _,
+        hostname: _,
+        target_hosts: _,
+      } => "incorrect host for dependency",
+      ErrorKind::ConditionNotMetError {
+        dependency: _,
+        condition: _,
+      } => "condition not met for dependency",
+      ErrorKind::InvalidConditionError {
+        dependency: _,
+        condition: _,
+        reason: _,
+      } => "invalid condition",
+      ErrorKind::CyclicalDependencyError { cycle: _ } => "cyclical dependency",
       ErrorKind::Other => "other error",
     }
   }
@@ -87,8 +152,16 @@ impl ErrorKind {
 impl fmt::Display for HMError {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     match *self {
+      HMError::Regular(ErrorKind::ConfigError {
+        line_number: Some(l),
+        ref reason,
+      }) => write!(f, "line {}: {}", l, reason),
+      HMError::Regular(ErrorKind::ConfigError {
+        line_number: None,
+        ref reason,
+      }) => write!(f, "{}", reason),
       HMError::Regular(ref err) => write!(f, "{:?}", err),
-      HMError::Other(ref err) => write!(f, "{:?}", err),
+      HMError::Other(ref err) => write!(f, "{}", err),
       HMError::Io(ref err) => err.fmt(f),
     }
   }
@@ -12,12 +12,12 @@
 //! into task threads, which regularly report back their status with
 //! Worker objects over a `std::sync::mpsc`.
 //!
-//! `hm` can also perform dependency resolution, thanks to the solvent crate. You can
+//! `hm` can also perform dependency resolution, with its own little `depgraph`. You can
 //! provide a big ol' list of tasks to complete, each with their own dependencies, and
 //! as long as you have a solveable dependency graph, you can get workable batches from
-//! get_task_batches(). They will be in *some* order that will resolve your dependencies,
-//! but that order is non-deterministic - if there's multiple ways to solve a graph,
-//! you'll probably get all those different ways back if you run it multiple times.
+//! get_task_batches(). They'll be in an order that resolves your dependencies, and
+//! where there's more than one way to do that, whatever comes first in `config.toml`
+//! goes first - the same order every run, on every machine.
 //!
 //! The crate provides this library, which is in turn used by the bin `hm` (in `src/bin/main.rs`).
 //! `hm` is a commandline program to help with dotfile (and more) management.
@@ -27,6 +27,7 @@
 //!
 //!  1. create a config.toml file either anywhere or in ~/.config/homemaker/.
 //!  2. enter things to do things to in the file.
+//!
 //!  example:
 //!  ``` text
 //!  ## config.toml
@@ -57,7 +58,6 @@
 //!  serde
 //!  toml
 //!  symlink
-//!  solvent
 //!  indicatif
 //!  console
 #![allow(clippy::many_single_char_names)]
@@ -68,73 +68,117 @@ extern crate indicatif;
 extern crate log;
 extern crate shellexpand;
 extern crate simplelog;
-extern crate solvent;
 extern crate symlink;
 extern crate sys_info;
 
+pub mod condition;
 pub mod config;
+pub mod depgraph;
+pub mod facts;
+pub mod graph;
 mod hm_macro;
 pub mod hmerror;
+pub mod state;
 
 use config::{ManagedObject, Worker};
+use depgraph::{DepGraph, DepGraphError};
 use hmerror::{ErrorKind as hmek, HMError};
 
+use chrono::prelude::*;
 use console::{pad_str, style, Alignment};
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use log::{info, warn};
-use solvent::DepGraph;
+use serde::Serialize;
+use state::{Outcome, State};
 use std::{
-  collections::{HashMap, HashSet},
-  fmt,
-  fs::{copy, create_dir_all, metadata, remove_dir_all, remove_file},
-  io::{BufRead, BufReader, Error, ErrorKind},
-  path::Path,
+  collections::{HashMap, HashSet, VecDeque},
+  fs::{copy, create_dir_all, metadata, read_link, remove_dir_all, remove_file, File},
+  io::{BufRead, BufReader, Error, Read, Write},
+  path::{Path, PathBuf},
   process::{exit, Command, Stdio},
-  sync::mpsc::{self, Sender},
+  sync::{
+    mpsc::{self, Sender},
+    Arc, Mutex,
+  },
   {thread, time},
 };
 use symlink::{symlink_dir as sd, symlink_file as sf};
 
-/// I just wanna borrow one to look at it for a minute.
-/// use me with std::mem::transmute()
-/// absolutely not pub struct. don't look at me.
+///
+/// Knobs for a single `do_tasks()` run.
+///
 #[derive(Debug, Clone)]
-struct SneakyDepGraphImposter<String> {
-  nodes: Vec<String>,
-  dependencies: HashMap<usize, HashSet<usize>>,
-  satisfied: HashSet<usize>,
+pub struct RunOptions {
+  /// Where to read and write our `State`. `None` means we don't remember anything between runs.
+  pub state_file: Option<PathBuf>,
+  /// Run every task, even the ones `State` says haven't changed since they last succeeded.
+  pub ignore_state: bool,
+  /// Only run the tasks that didn't complete last time - the ones that failed, got skipped
+  /// because a dependency failed, or never started.
+  pub resume: bool,
+  /// This run's log file. Each task's output gets a log file of its own next to it.
+  pub log_file: Option<PathBuf>,
+  /// How many lines of a failed task's output to show in the summary.
+  pub tail_lines: usize,
+  /// Only do objects with one of these tags (and what they depend on). Empty means everything.
+  pub tags: Vec<String>,
+  /// Leave out objects with any of these tags, unless something we're doing depends on them.
+  pub skip_tags: Vec<String>,
+  /// Leave out these objects, and whatever depends on them.
+  pub exclude: Vec<String>,
+  /// No progress bars and no summary - whoever called us is reporting how it went.
+  pub quiet: bool,
 }
 
-impl fmt::Display for SneakyDepGraphImposter<String> {
-  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    let mut i: usize = 0;
-    for n in self.nodes.clone() {
-      if self.dependencies.get(&i).is_some() {
-        let _ = write!(f, "[ {} -> ", n);
-        #[allow(clippy::for_loops_over_fallibles)]
-        for d in self.dependencies.get(&i) {
-          if d.is_empty() {
-            write!(f, "<no deps> ")?;
-          }
-          let mut j: usize = 1;
-          for m in d {
-            if j == d.len() {
-              write!(f, "{} ", self.nodes[*m])?;
-            } else {
-              write!(f, "{}, ", self.nodes[*m])?;
-            }
-            j += 1;
-          }
-        }
-      }
-      i += 1;
-      if i != self.nodes.len() {
-        write!(f, "], ")?;
-      } else {
-        write!(f, "]")?;
-      }
+impl Default for RunOptions {
+  fn default() -> Self {
+    RunOptions {
+      state_file: None,
+      ignore_state: false,
+      resume: false,
+      log_file: None,
+      tail_lines: 10,
+      tags: Vec::new(),
+      skip_tags: Vec::new(),
+      exclude: Vec::new(),
+      quiet: false,
     }
-    Ok(())
+  }
+}
+
+///
+/// How a `run_tasks()` run went, object by object, in the order they were taken on.
+///
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+  pub objects: Vec<ObjectReport>,
+}
+
+///
+/// How one file or task fared. `exit_code` and `duration_ms` are only there
+/// for objects that actually ran; `output` is the last few lines it printed.
+///
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ObjectReport {
+  pub name: String,
+  /// `file` or `task`.
+  pub kind: String,
+  pub outcome: Outcome,
+  pub exit_code: Option<i32>,
+  pub duration_ms: Option<u64>,
+  pub output: Vec<String>,
+  /// Everything it printed, if it ran and we're keeping logs.
+  pub log: Option<PathBuf>,
+}
+
+impl Report {
+  /// Everything that didn't succeed (or didn't need to).
+  pub fn failed(&self) -> Vec<&ObjectReport> {
+    self
+      .objects
+      .iter()
+      .filter(|o| !o.outcome.is_done())
+      .collect()
   }
 }
 
@@ -169,6 +213,10 @@ pub fn copy_item(source: String, target: String, force: bool) -> Result<(), HMEr
 pub fn symlink_item(source: String, target: String, force: bool) -> Result<(), HMError> {
   let _lsource: String = shellexpand::tilde(&source).to_string();
   let _ltarget: String = shellexpand::tilde(&target).to_string();
+  // already pointing where it should - nothing to do
+  if read_link(&_ltarget).is_ok_and(|l| l == Path::new(&_lsource)) {
+    return Ok(());
+  }
   let md = match metadata(_lsource.clone()) {
     Ok(a) => a,
     Err(e) => return Err(HMError::Io(e)),
@@ -198,6 +246,9 @@ pub fn symlink_item(source: String, target: String, force: bool) -> Result<(), H
 ///
 /// -TODO-: allow the `verbose` bool to show the output of the tasks as they go.
 /// Hey, it's done! Writes out to the logs/ directory.
+/// Both stdout and stderr are captured, timestamped, and written (interleaved) to
+/// a log file of the task's own next to `opts.log_file`. The last `opts.tail_lines` of
+/// them come back in the final Worker, so we can show what went wrong.
 ///
 /// Return () or io::Error (something went wrong in our task).
 ///
@@ -205,55 +256,82 @@ pub fn send_tasks_off_to_college(
   mo: &ManagedObject,
   tx: &Sender<Worker>,
   p: ProgressBar,
+  opts: &RunOptions,
 ) -> Result<(), Error> {
-  let s: String = mo.solution.clone();
-  let s1: String = mo.solution.clone();
+  let mut cmd: Command = if mo.script.is_empty() {
+    solution_command(mo, &mo.solution, false)
+  } else {
+    solution_command(mo, &shellexpand::tilde(&mo.script), true)
+  };
+  let s1: String = if mo.script.is_empty() {
+    mo.solution.clone()
+  } else {
+    mo.script.clone()
+  };
   let n: String = mo.name.clone();
+  let prefix: String = object_prefix(mo);
   let tx1: Sender<Worker> = Sender::clone(tx);
+  let capture = capture_for(&n, opts);
   let _: thread::JoinHandle<Result<(), HMError>> = thread::spawn(move || {
-    let mut c = Command::new("bash")
-      .arg("-c")
-      .arg(s)
-      .stdout(Stdio::piped())
-      .stderr(Stdio::piped())
-      .spawn()
-      .unwrap();
-    let output: std::process::ChildStdout = c.stdout.take().unwrap();
-    let reader: BufReader<std::process::ChildStdout> = BufReader::new(output);
-    // run this in another thread or the little block of tasks draws line-by-line
-    // instead of all at once then updating as the task info gets Worker'd back
-    thread::spawn(|| {
-      reader
-        .lines()
-        .filter_map(|line| line.ok())
-        .for_each(|line| info!("{}", line));
-    });
+    let mut c = match cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn() {
+      Ok(c) => c,
+      Err(e) => {
+        // couldn't even get started (missing `cwd`, say) - that's a failure like any other
+        warn!("Couldn't start `{}`: {}", s1, e);
+        capture.lock().unwrap().line("hm", &e.to_string());
+        tx1
+          .send(Worker {
+            name: n,
+            status: None,
+            completed: true,
+            output: capture.lock().unwrap().tail.iter().cloned().collect(),
+          })
+          .unwrap();
+        p.abandon_with_message(console::style("✗").red().to_string());
+        return Err(HMError::Io(e));
+      }
+    };
+    // read both in their own threads - otherwise the little block of tasks draws line-by-line
+    // instead of all at once, and a chatty stream nobody's reading fills its pipe and wedges the task
+    let readers: Vec<thread::JoinHandle<()>> = vec![
+      read_lines(c.stdout.take().unwrap(), "stdout", Arc::clone(&capture)),
+      read_lines(c.stderr.take().unwrap(), "stderr", Arc::clone(&capture)),
+    ];
     p.set_style(
       ProgressStyle::default_spinner()
         .template("[{elapsed:4}] {prefix:.bold.dim} {spinner} {wide_msg}"),
     );
     p.enable_steady_tick(200);
-    let x = pad_str(format!("task {}", n).as_str(), 30, Alignment::Left, None).into_owned();
-    p.set_prefix(x);
+    p.set_prefix(prefix);
+    p.set_message("");
+    p.reset_elapsed();
     loop {
       let mut w: Worker = Worker {
         name: n.clone(),
         status: None,
         completed: false,
+        output: Vec::new(),
       };
       match c.try_wait() {
         // we check each child status...
         Ok(Some(status)) => {
+          // make sure we've got every last line before we report back
+          for r in readers {
+            let _ = r.join();
+          }
+          w.status = status.code();
+          w.completed = true;
+          w.output = capture.lock().unwrap().tail.iter().cloned().collect();
           if status.success() {
             // if we're done, send back :thumbsup:
             p.finish_with_message(console::style("✓").green().to_string());
-            w.status = status.code();
-            w.completed = true;
             tx1.send(w).unwrap();
             info!("Successfully completed {}.", n);
             return Ok(());
           } else {
             // or :sadface:
+            // still tell them we're done, or they'll wait on us forever
+            tx1.send(w).unwrap();
             drop(tx1);
             warn!("Error within `{}`", s1);
             p.abandon_with_message(console::style("✗").red().to_string());
@@ -266,6 +344,9 @@ pub fn send_tasks_off_to_college(
         }
         Err(_e) => {
           // ahh send back err!
+          w.completed = true;
+          w.output = capture.lock().unwrap().tail.iter().cloned().collect();
+          tx1.send(w).unwrap();
           drop(tx1);
           p.abandon_with_message(console::style("✗").red().to_string());
           return Err(HMError::Regular(hmek::SolutionError { solution: s1 }));
@@ -276,12 +357,171 @@ pub fn send_tasks_off_to_college(
   Ok(())
 }
 
+///
+/// Take a file ManagedObject, an mpsc tx, and a Progressbar. Symlink or copy it into place,
+/// then run its `post` (if it has one), all in a thread - reporting back with a Worker
+/// just like send_tasks_off_to_college(), so files and tasks can depend on each other.
+/// Anything `post` has to say goes to the object's own log file, same as a task's output.
+///
+pub fn put_file_in_place(
+  mo: &ManagedObject,
+  tx: &Sender<Worker>,
+  p: ProgressBar,
+  opts: &RunOptions,
+) {
+  let mo: ManagedObject = mo.clone();
+  let tx1: Sender<Worker> = Sender::clone(tx);
+  let capture = capture_for(&mo.name, opts);
+  p.set_style(
+    ProgressStyle::default_spinner()
+      .template("[{elapsed:4}] {prefix:.bold.dim} {spinner} {wide_msg}"),
+  );
+  p.enable_steady_tick(200);
+  p.set_prefix(object_prefix(&mo));
+  p.set_message(
+    style(format!("{} → {}", mo.source, mo.destination))
+      .dim()
+      .to_string(),
+  );
+  p.reset_elapsed();
+  thread::spawn(move || {
+    let status: Option<i32> = match perform_operation_on(mo.clone()) {
+      Ok(()) if mo.post.is_empty() => Some(0),
+      Ok(()) => {
+        let mut c = solution_command(&mo, &mo.post, false);
+        match c.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn() {
+          Ok(mut c) => {
+            let readers: Vec<thread::JoinHandle<()>> = vec![
+              read_lines(c.stdout.take().unwrap(), "stdout", Arc::clone(&capture)),
+              read_lines(c.stderr.take().unwrap(), "stderr", Arc::clone(&capture)),
+            ];
+            let status = c.wait().ok().and_then(|s| s.code());
+            for r in readers {
+              let _ = r.join();
+            }
+            status
+          }
+          Err(e) => {
+            warn!("Couldn't start post `{}` for {}: {}", mo.post, mo.name, e);
+            capture.lock().unwrap().line("hm", &e.to_string());
+            None
+          }
+        }
+      }
+      Err(e) => {
+        warn!("Failed to perform operation on {}: {}", mo.name, e);
+        capture.lock().unwrap().line("hm", &e.to_string());
+        None
+      }
+    };
+    if status == Some(0) {
+      info!("Successfully performed operation on {}.", mo.name);
+      p.finish_with_message(console::style("✓").green().to_string());
+    } else {
+      p.abandon_with_message(console::style("✗").red().to_string());
+    }
+    // always tell them we're done, or they'll wait on us forever
+    tx1
+      .send(Worker {
+        name: mo.name.clone(),
+        status,
+        completed: true,
+        output: capture.lock().unwrap().tail.iter().cloned().collect(),
+      })
+      .unwrap();
+  });
+}
+
+///
+/// Somewhere for an object's output to go, with a log file of its own next to `opts.log_file`.
+///
+fn capture_for(n: &str, opts: &RunOptions) -> Arc<Mutex<Capture>> {
+  let log: Option<File> = match &opts.log_file {
+    Some(l) => match File::create(task_log_path(l, n)) {
+      Ok(f) => Some(f),
+      Err(e) => {
+        warn!("Couldn't create log file for {}. Error: {}", n, e);
+        None
+      }
+    },
+    None => None,
+  };
+  Arc::new(Mutex::new(Capture {
+    log,
+    tail: VecDeque::new(),
+    tail_lines: opts.tail_lines,
+  }))
+}
+
+///
+/// Where a task's output ends up: its own log file (if it has one), and
+/// the last few lines, in case we need to show them to somebody.
+///
+struct Capture {
+  log: Option<File>,
+  tail: VecDeque<String>,
+  tail_lines: usize,
+}
+
+impl Capture {
+  fn line(&mut self, stream: &str, line: &str) {
+    if let Some(f) = self.log.as_mut() {
+      let _ = writeln!(
+        f,
+        "{} [{}] {}",
+        Local::now().format("%H:%M:%S%.3f"),
+        stream,
+        line
+      );
+    }
+    if self.tail_lines > 0 {
+      if self.tail.len() == self.tail_lines {
+        self.tail.pop_front();
+      }
+      self.tail.push_back(line.to_string());
+    }
+  }
+}
+
+fn read_lines<R: Read + Send + 'static>(
+  r: R,
+  stream: &'static str,
+  capture: Arc<Mutex<Capture>>,
+) -> thread::JoinHandle<()> {
+  thread::spawn(move || {
+    BufReader::new(r)
+      .lines()
+      .map_while(Result::ok)
+      .for_each(|line| {
+        info!("{}", line);
+        capture.lock().unwrap().line(stream, &line);
+      });
+  })
+}
+
+///
+/// Each task's log sits next to the run's: `hm-task-<when>.log` gets `hm-task-<when>.<task>.log`.
+///
+pub fn task_log_path(run_log: &Path, task: &str) -> PathBuf {
+  let stem = run_log
+    .file_stem()
+    .map(|s| s.to_string_lossy().to_string())
+    .unwrap_or_default();
+  let task: String = task
+    .chars()
+    .map(|c| if c == '/' || c == '\\' { '_' } else { c })
+    .collect();
+  run_log.with_file_name(format!("{}.{}.log", stem, task))
+}
+
 /*
 */
 ///
-/// Create a non-cyclical dependency graph and give it back as a Vec&lt;Vec&lt;ManagedObject&gt;&gt;.
-/// Will return a CyclicalDependencyError if the graph is unsolveable.
-/// Intended to be used with either mgmt::execute_solution or mgmt::send_tasks_off_to_college.
+/// Create a non-cyclical dependency graph and give it back as a Vec&lt;Vec&lt;ManagedObject&gt;&gt;
+/// of layers: everything in a layer depends only on things in the layers before it,
+/// so a layer's objects can all be done at once. Within a layer, `config.toml` order.
+/// Will return a CyclicalDependencyError, naming the loop (`a -> b -> a`), if the graph is unsolveable.
+/// Intended to be used with either put_file_in_place or send_tasks_off_to_college.
 ///
 ///
 /// Example:
@@ -289,182 +529,227 @@ pub fn send_tasks_off_to_college(
 /// ```
 /// extern crate indicatif;
 /// use std::sync::mpsc;
-/// use std::collections::HashMap;
+/// use std::collections::{HashMap, HashSet};
 /// use indicatif::{MultiProgress, ProgressBar};
 /// use hm::config::ManagedObject;
-/// use hm::{get_task_batches, send_tasks_off_to_college};
+/// use hm::{get_task_batches, send_tasks_off_to_college, RunOptions};
 /// let nodes: HashMap<String, ManagedObject> = HashMap::new();
 /// let (tx, rx) = mpsc::channel();
 /// let mp: MultiProgress = MultiProgress::new();
-/// let v: Vec<Vec<ManagedObject>> = get_task_batches(nodes, None).unwrap();
+/// let v: Vec<Vec<ManagedObject>> = get_task_batches(nodes, HashSet::new()).unwrap();
 /// for a in v {
 ///   for b in a {
 ///     let _p: ProgressBar = mp.add(ProgressBar::new_spinner());
-///     send_tasks_off_to_college(&b, &tx, _p);
+///     send_tasks_off_to_college(&b, &tx, _p, &RunOptions::default());
 ///   }
 /// }
 /// ```
 ///
 pub fn get_task_batches(
   mut nodes: HashMap<String, ManagedObject>,
-  target_task: Option<String>,
+  targets: HashSet<String>,
 ) -> Result<Vec<Vec<ManagedObject>>, HMError> {
-  let our_os = config::determine_os();
-  let mut depgraph: DepGraph<String> = DepGraph::new();
+  let platform = config::Platform::detect();
+  let mut depgraph: DepGraph = DepGraph::new();
   let mut nodes_to_remove: Vec<String> = Vec::new();
-  let mut wrong_platforms: HashMap<String, config::OS> = HashMap::new();
-  for (name, node) in &nodes {
-    if node.os.is_none() || node.os.clone().unwrap() == our_os {
-      depgraph.register_dependencies(name.to_owned(), node.dependencies.clone());
-    } else {
-      nodes_to_remove.push(name.to_string());
-      wrong_platforms.insert(name.clone(), node.os.clone().unwrap());
+  // what to complain about, should anything depend on something that doesn't belong here
+  let mut excluded: HashMap<String, hmek> = HashMap::new();
+  // when dependencies don't decide it, config.toml order does. undefined names go last.
+  let declared: HashMap<String, usize> = nodes.iter().map(|(n, m)| (n.clone(), m.order)).collect();
+  let by_declaration = |a: &String, b: &String| {
+    (declared.get(a).unwrap_or(&usize::MAX), a).cmp(&(declared.get(b).unwrap_or(&usize::MAX), b))
+  };
+  let mut names: Vec<&String> = nodes.keys().collect();
+  names.sort_by(|a, b| by_declaration(a, b));
+  for name in names {
+    let node = &nodes[name];
+    match applies_to(node, &platform) {
+      Ok(()) => {
+        let mut deps: Vec<String> = node.dependencies.clone();
+        deps.sort_by(by_declaration);
+        depgraph.register_dependencies(name, &deps);
+      }
+      // a typo in `when =` shouldn't quietly drop the object
+      Err(e @ hmek::InvalidConditionError { .. }) => return Err(HMError::Regular(e)),
+      Err(e) => {
+        nodes_to_remove.push(name.to_string());
+        excluded.insert(name.clone(), e);
+      }
     }
   }
   for n in nodes_to_remove {
     nodes.remove(&n);
   }
-  let mut tasks: Vec<Vec<ManagedObject>> = Vec::new();
-  let mut _dedup: HashSet<String> = HashSet::new();
+  // first, everything we need: the roots and their dependencies, all the way down
+  let mut needed: Vec<String> = Vec::new();
+  let mut seen: HashSet<String> = HashSet::new();
   /*
-    ok. we've got a target task. we can get the depgraph for ONLY that task,
+    ok. if we've got target tasks, we can get the depgraph for ONLY those,
     and don't need to go through our entire config to solve for each.
-    just the subtree involved in our target.
+    just the subtrees involved in our targets.
   */
-  if let Some(tt_name) = target_task {
-    // TODO: break out getting our tasklist into its own function
-    // de-dup me!
-    let mut qtdg: Vec<ManagedObject> = Vec::new();
-    let tdg: solvent::DepGraphIterator<String> = match depgraph.dependencies_of(&tt_name) {
-      Ok(i) => i,
-      Err(_) => {
-        return Err(HMError::Regular(hmek::DependencyUndefinedError {
-          dependency: tt_name,
+  let mut roots: Vec<String> = if targets.is_empty() {
+    nodes.keys().cloned().collect()
+  } else {
+    targets.into_iter().collect()
+  };
+  roots.sort_by(by_declaration);
+  for name in roots {
+    let dg: Vec<String> = match depgraph.dependencies_of(&name) {
+      Ok(d) => d,
+      Err(DepGraphError::Cycle(c)) => {
+        return Err(HMError::Regular(hmek::CyclicalDependencyError {
+          cycle: c.join(" -> "),
+        }))
+      }
+      Err(DepGraphError::NoSuchNode(_)) => {
+        // a target we've never heard of, or one that doesn't belong here
+        return Err(HMError::Regular(match excluded.get(&name) {
+          Some(e) => e.clone(),
+          None => hmek::DependencyUndefinedError { dependency: name },
         }));
       }
     };
-    for n in tdg {
-      match n {
-        Ok(r) => {
-          let mut a = match nodes.get(r) {
-            Some(a) => a,
-            None => {
-              /*
-              if we have a dependency, but it can't be solved because it's for the incorrect platform,
-              let's complain about it.
-              doing it this way is necessary because we DO still want our dependency graph to get run.
-              */
-              if wrong_platforms.contains_key(r) {
-                return Err(HMError::Regular(hmek::IncorrectPlatformError {
-                  dependency: String::from(r),
-                  platform: our_os,
-                  target_platform: wrong_platforms.get(r).cloned().unwrap(),
-                }));
-              } else {
-                return Err(HMError::Regular(hmek::DependencyUndefinedError {
-                  dependency: String::from(r),
-                }));
-              }
-            }
-          }
-          .to_owned();
-          a.set_satisfied();
-          qtdg.push(a);
-        }
-        Err(_e) => unsafe {
-          // we'll just borrow this for a second
-          // just to look
-          // i'm not gonna touch it i promise
-          let my_sneaky_depgraph: SneakyDepGraphImposter<String> = std::mem::transmute(depgraph);
-          return Err(HMError::Regular(hmek::CyclicalDependencyError {
-            // we can do this because we've implemented fmt::Display for SneakyDepGraphImposter
-            dependency_graph: my_sneaky_depgraph.to_string(),
-          }));
-        },
+    for r in dg {
+      // returns true if the set DID NOT have r in it already
+      if !seen.insert(r.clone()) {
+        continue;
+      }
+      if !nodes.contains_key(&r) {
+        /*
+        if we have a dependency, but it can't be solved because it's for the incorrect platform,
+        let's complain about it.
+        doing it this way is necessary because we DO still want our dependency graph to get run.
+        */
+        return Err(HMError::Regular(match excluded.get(&r) {
+          Some(e) => e.clone(),
+          None => hmek::DependencyUndefinedError { dependency: r },
+        }));
       }
+      needed.push(r);
     }
-    tasks.push(qtdg);
-  } else {
-    // not doing target task
-    // we're doing the entire config
-    for name in nodes.keys() {
-      let mut q: Vec<ManagedObject> = Vec::new();
-      let dg: solvent::DepGraphIterator<String> = depgraph.dependencies_of(name).unwrap();
-      for n in dg {
-        match n {
-          Ok(r) => {
-            let c = String::from(r.as_str());
-            // returns true if the set DID NOT have c in it already
-            if _dedup.insert(c) {
-              let mut a = match nodes.get(r) {
-                Some(a) => a,
-                None => {
-                  /*
-                  if we have a dependency, but it can't be solved because it's for the incorrect platform,
-                  let's complain about it.
-                  doing it this way is necessary because we DO still want our dependency graph to get run.
-                  */
-                  if wrong_platforms.contains_key(r) {
-                    return Err(HMError::Regular(hmek::IncorrectPlatformError {
-                      dependency: String::from(r),
-                      platform: our_os,
-                      target_platform: wrong_platforms.get(r).cloned().unwrap(),
-                    }));
-                  } else {
-                    return Err(HMError::Regular(hmek::DependencyUndefinedError {
-                      dependency: String::from(r),
-                    }));
-                  }
-                }
-              }
-              .to_owned();
-              a.set_satisfied();
-              q.push(a);
-            }
-          }
-          Err(_e) => unsafe {
-            // we'll just borrow this for a second
-            // just to look
-            // i'm not gonna touch it i promise
-            let my_sneaky_depgraph: SneakyDepGraphImposter<String> = std::mem::transmute(depgraph);
-            return Err(HMError::Regular(hmek::CyclicalDependencyError {
-              // we can do this because we've implemented fmt::Display for SneakyDepGraphImposter
-              dependency_graph: my_sneaky_depgraph.to_string(),
-            }));
-          },
-        }
+  }
+  // then what order to do them in. `after` counts here, but only for what's in this run -
+  // it never drags anything in, and doesn't mind if what it names isn't here
+  let before = |mo: &ManagedObject| -> Vec<String> {
+    let mut b: Vec<String> = mo.dependencies.clone();
+    b.extend(mo.after.iter().filter(|a| seen.contains(*a)).cloned());
+    b.sort_by(by_declaration);
+    b.dedup();
+    b
+  };
+  needed.sort_by(by_declaration);
+  let mut ordering: DepGraph = DepGraph::new();
+  for n in &needed {
+    ordering.register_dependencies(n, &before(&nodes[n]));
+  }
+  // which batch each object's in: one past the last of what has to come before it
+  let mut layer_of: HashMap<String, usize> = HashMap::new();
+  let mut layers: Vec<Vec<ManagedObject>> = Vec::new();
+  for name in &needed {
+    let dg: Vec<String> = match ordering.dependencies_of(name) {
+      Ok(d) => d,
+      // `dependencies` on their own were fine, so this is `after` going round in circles
+      Err(e) => {
+        return Err(HMError::Regular(hmek::CyclicalDependencyError {
+          cycle: e.to_string(),
+        }))
+      }
+    };
+    // what comes before always comes first, so theirs are already worked out
+    for r in dg {
+      if layer_of.contains_key(&r) {
+        continue;
       }
-      tasks.push(q);
+      let a = nodes[&r].to_owned();
+      let l = before(&a)
+        .iter()
+        .filter_map(|d| layer_of.get(d))
+        .max()
+        .map_or(0, |l| l + 1);
+      if l == layers.len() {
+        layers.push(Vec::new());
+      }
+      layer_of.insert(r, l);
+      layers[l].push(a);
     }
   }
-  Ok(tasks)
+  for l in layers.iter_mut() {
+    l.sort_by(|a, b| by_declaration(&a.name, &b.name));
+  }
+  Ok(layers)
+}
+
+///
+/// Put together the `Command` for a solution: hand it to the object's `shell` (`bash -c`
+/// unless told otherwise) from its `cwd` (if it has one), with its `env` on top of our
+/// own environment. If `script` is set, `solution` is the path of a script file to run instead.
+///
+fn solution_command(mo: &ManagedObject, solution: &str, script: bool) -> Command {
+  let argv: Vec<String> = mo.shell.argv(solution, script);
+  let mut c = Command::new(&argv[0]);
+  c.args(&argv[1..]);
+  if !mo.cwd.is_empty() {
+    c.current_dir(shellexpand::tilde(&mo.cwd).to_string());
+  }
+  for (k, v) in &mo.env {
+    c.env(k, shellexpand::tilde(v).to_string());
+  }
+  c
 }
 
 ///
-/// Execute the shell commands specified in the MO's solution in a thread, so as not to block.
-///
-fn execute_solution(solution: String) -> Result<(), HMError> {
-  // marginally adapted but mostly stolen from
-  // https://rust-lang-nursery.github.io/rust-cookbook/os/external.html
-
-  let child: thread::JoinHandle<Result<(), HMError>> = thread::spawn(|| {
-    let output = Command::new("bash")
-      .arg("-c")
-      .arg(solution)
-      .stdout(Stdio::piped())
-      .spawn()?
-      .stdout
-      .ok_or_else(|| Error::new(ErrorKind::Other, "Couldn't capture stdout"))?;
-    if cfg!(debug_assertions) {
-      let reader = BufReader::new(output);
-      reader
-        .lines()
-        .filter_map(|line| line.ok())
-        .for_each(|line| println!("{}", line));
+/// Does this object belong on this machine? If not, hand back what we'd complain about
+/// should something depend on it.
+///
+pub fn applies_to(mo: &ManagedObject, platform: &config::Platform) -> Result<(), hmek> {
+  if !mo.os.is_empty() && !mo.os.iter().any(|o| platform.matches(o)) {
+    return Err(hmek::IncorrectPlatformError {
+      dependency: mo.name.clone(),
+      platform: platform.os.clone(),
+      target_platform: mo.os.clone(),
+    });
+  }
+  if let Some(o) = mo.not_os.iter().find(|o| platform.matches(o)) {
+    return Err(hmek::ExcludedPlatformError {
+      dependency: mo.name.clone(),
+      platform: platform.os.clone(),
+      excluded_platform: o.clone(),
+    });
+  }
+  if !platform.matches_arch(&mo.arch) {
+    return Err(hmek::IncorrectArchError {
+      dependency: mo.name.clone(),
+      arch: platform.arch.clone(),
+      target_arch: mo.arch.clone(),
+    });
+  }
+  if !platform.matches_host(&mo.hosts) {
+    return Err(hmek::IncorrectHostError {
+      dependency: mo.name.clone(),
+      hostname: platform.hostname.clone(),
+      target_hosts: mo.hosts.clone(),
+    });
+  }
+  if !mo.when.is_empty() {
+    match condition::Condition::parse(&mo.when) {
+      Ok(c) if c.holds(platform) => {}
+      Ok(_c) => {
+        return Err(hmek::ConditionNotMetError {
+          dependency: mo.name.clone(),
+          condition: mo.when.clone(),
+        })
+      }
+      Err(reason) => {
+        return Err(hmek::InvalidConditionError {
+          dependency: mo.name.clone(),
+          condition: mo.when.clone(),
+          reason,
+        })
+      }
     }
-    Ok(())
-  });
-  child.join().unwrap()
+  }
+  Ok(())
 }
 
 ///
@@ -484,99 +769,522 @@ pub fn perform_operation_on(mo: ManagedObject) -> Result<(), HMError> {
       let destination: String = mo.destination;
       copy_item(source, destination, mo.force)
     }
-    _ => {
-      println!("{}", style(_s.to_string()).red());
-      Ok(())
-    }
+    _ => Err(HMError::Regular(hmek::ConfigError {
+      line_number: None,
+      reason: format!("`{}` isn't a method we know - `symlink` or `copy`", _s),
+    })),
   }
 }
 
 ///
-/// Take our list of ManagedObjects to do stuff to, and determine
-/// if they're simple or complex (simple is symlink or copy, complex
-/// maybe compilation or pulling a git repo). We just do the simple ones, as they
-/// won't be computationally expensive.
+/// Take our list of ManagedObjects to do stuff to (those picked by `opts.tags` and
+/// `opts.skip_tags`, less `opts.exclude`; only `targets` and what they depend on, if
+/// there are any), and get a list of list of MOs that we can do in some order that
+/// satisfies their dependencies - files and tasks alike, so either can depend on the other.
+/// Tasks get handed off to send_tasks_off_to_college(), and files (symlink or copy,
+/// then their `post`) to put_file_in_place().
+///
+/// Tasks our `State` says haven't changed since they last succeeded are marked
+/// `satisfied` and skipped, unless `opts.ignore_state` is set. With `opts.resume`,
+/// so is anything that completed in the last run.
 ///
-/// For complex ones we get a list of list of MOs that we can do in some order that
-/// satisfies their dependencies, then we hand them off to send_tasks_off_to_college().
+/// Returns an error naming the files and tasks that didn't complete, if any.
 ///
 pub fn do_tasks(
   a: HashMap<String, config::ManagedObject>,
-  target_task: Option<String>,
+  targets: HashSet<String>,
+  opts: RunOptions,
 ) -> Result<(), HMError> {
-  let mut complex_operations = a.clone();
-  let mut simple_operations = a;
-  complex_operations.retain(|_, v| v.is_task()); // all the things that aren't just symlink/copy
-  simple_operations.retain(|_, v| !v.is_task()); // all the things that are quick (don't need to thread off)
-  if target_task.is_some() {
-    let tt_name = target_task.clone().unwrap();
-    // only keep the target task, if it's in here...
-    // we can't do this with complex tasks, because the target may have deps we want
-    // we'll handle that later in get_task_batches
-    simple_operations.retain(|_, v| v.name == tt_name);
-  }
-  for (_name, _mo) in simple_operations.into_iter() {
-    // lol postmaclone
-    let p = _mo.post.clone();
-    let a = perform_operation_on(_mo).map_err(|e| {
-      hmerror::error(
-        format!("Failed to perform operation on {:#?}", _name).as_str(),
-        e.to_string().as_str(),
-      )
-    });
-    if a.is_ok() {
-      hmerror::happy_print(format!("Successfully performed operation on {:#?}", _name).as_str());
-      if !p.is_empty() {
-        println!("↳ Executing post {} for {}... ", p, _name);
-        let _ = execute_solution(p);
-      }
-    }
-  }
-  let (tx, rx) = mpsc::channel();
-  let mp: MultiProgress = MultiProgress::new();
-  let mut t: HashSet<String> = HashSet::new();
-  let _v = get_task_batches(complex_operations, target_task).unwrap_or_else(|er| {
+  let report = run_tasks(a, targets, opts).unwrap_or_else(|er| {
     hmerror::error(
       "Error occurred attempting to get task batches",
       format!("{}{}", "\n", er.to_string().as_str()).as_str(),
     );
     exit(3);
   });
-  for _a in _v {
-    for _b in _a {
-      t.insert(_b.name.to_string());
-      let _p: ProgressBar = mp.add(ProgressBar::new_spinner());
-      send_tasks_off_to_college(&_b, &tx, _p).expect("ohtehnoes");
+  let failed = report.failed();
+  if failed.is_empty() {
+    Ok(())
+  } else {
+    Err(HMError::Other(format!(
+      "{} object(s) didn't complete: {}. Fix what's wrong and pick up where we left off with `hm --resume`.",
+      failed.len(),
+      failed
+        .iter()
+        .map(|o| o.name.as_str())
+        .collect::<Vec<&str>>()
+        .join(", ")
+    )))
+  }
+}
+
+///
+/// What `do_tasks()` does, minus the deciding what counts as failure: work through
+/// everything and hand back a `Report` of how each object went. The only error is
+/// not being able to put our objects in order in the first place.
+///
+pub fn run_tasks(
+  a: HashMap<String, config::ManagedObject>,
+  targets: HashSet<String>,
+  opts: RunOptions,
+) -> Result<Report, HMError> {
+  let a = config::select_by_tags(a, &opts.tags, &opts.skip_tags);
+  let a = config::exclude(a, &opts.exclude);
+  let mp: MultiProgress = if opts.quiet {
+    MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+  } else {
+    MultiProgress::new()
+  };
+  // files and tasks go in the same graph, so either can depend on the other
+  let _v = get_task_batches(a, targets)?;
+  let mut state: State = match &opts.state_file {
+    Some(f) => State::load(f),
+    None => State::default(),
+  };
+  // one layer after another; within a layer, nothing waits on anything else
+  let mut order: Vec<ManagedObject> = _v.into_iter().flatten().collect();
+  if !opts.resume {
+    state.last_run.clear();
+  }
+  let use_state = opts.state_file.is_some() && !opts.ignore_state;
+  // anything that has to run drags everything that depends on it along, too
+  let mut rerun: HashSet<String> = HashSet::new();
+  let mut bars: Vec<ProgressBar> = Vec::new();
+  for mo in order.iter_mut() {
+    let _p: ProgressBar = mp.add(ProgressBar::new_spinner());
+    // files are cheap, and somebody may have moved ours since - always put them back
+    let already_done = (opts.resume && state.done_last_run(&mo.name))
+      || (use_state && mo.is_task() && state.is_unchanged(mo));
+    if already_done && !mo.dependencies.iter().any(|d| rerun.contains(d)) {
+      mo.set_satisfied();
+      if !state.done_last_run(&mo.name) {
+        state.last_run.insert(mo.name.clone(), Outcome::Unchanged);
+      }
+      info!("{} already taken care of, skipping.", mo.name);
+      finish_unchanged(&_p, mo);
+    } else {
+      // putting a file back where it already was doesn't make its dependents stale
+      if mo.is_task() || mo.dependencies.iter().any(|d| rerun.contains(d)) {
+        rerun.insert(mo.name.clone());
+      }
+      state.last_run.insert(mo.name.clone(), Outcome::NotStarted);
+      wait_in_line(&_p, mo);
     }
+    bars.push(_p);
+  }
+  // the bars only get drawn while we're join()ed on them, so the actual work
+  // has to happen somewhere else
+  let names: Vec<(String, &str)> = order
+    .iter()
+    .map(|o| (o.name.clone(), if o.is_task() { "task" } else { "file" }))
+    .collect();
+  let log_file = opts.log_file.clone();
+  let quiet = opts.quiet;
+  let runner = thread::spawn(move || run_in_order(order, bars, state, opts));
+  mp.join().unwrap();
+  let (state, finished, took) = runner.join().unwrap();
+  let report = Report {
+    objects: names
+      .iter()
+      .map(|(n, kind)| {
+        let w = finished.get(n);
+        ObjectReport {
+          name: n.clone(),
+          kind: kind.to_string(),
+          outcome: state
+            .last_run
+            .get(n)
+            .copied()
+            .unwrap_or(Outcome::NotStarted),
+          exit_code: w.and_then(|w| w.status),
+          duration_ms: took.get(n).map(|d| d.as_millis() as u64),
+          output: w.map(|w| w.output.clone()).unwrap_or_default(),
+          log: w.and(log_file.as_ref().map(|l| task_log_path(l, n))),
+        }
+      })
+      .collect(),
+  };
+  if !quiet {
+    print_failures(&report);
   }
+  Ok(report)
+}
 
-  let mut v: HashMap<String, config::Worker> = HashMap::new();
+/// What went wrong, for humans: each failure with its exit code and the end of its output.
+fn print_failures(report: &Report) {
+  for o in report.failed() {
+    match o.outcome {
+      Outcome::Failed => {
+        let code = match o.exit_code {
+          Some(c) => format!("exited {}", c),
+          None => String::from("didn't run to completion"),
+        };
+        eprintln!(
+          "{} {} {}",
+          style("✗").red(),
+          style(format!("{} {}", o.kind, o.name)).bold(),
+          style(code).dim()
+        );
+        for l in &o.output {
+          eprintln!("    {}", l);
+        }
+        if let Some(l) = &o.log {
+          eprintln!("    ↳ full output in {}", l.to_string_lossy());
+        }
+      }
+      Outcome::Skipped | Outcome::NotStarted => eprintln!(
+        "{} {} {}",
+        style("-").yellow(),
+        style(format!("{} {}", o.kind, o.name)).bold(),
+        style(if o.outcome == Outcome::Skipped {
+          "skipped, a dependency failed"
+        } else {
+          "never started"
+        })
+        .dim()
+      ),
+      Outcome::Succeeded | Outcome::Unchanged => {}
+    }
+  }
+}
 
-  // create a map of each Worker, and just poll them until they're done
-  // the hashmap ensures we have only one of each worker
+///
+/// Work through `order`, sending off each object as soon as everything it depends on
+/// has succeeded. If something it depends on fails, it's skipped instead.
+/// Every outcome goes into `state.last_run` (and onto disk) as soon as we know it,
+/// so even a run that gets interrupted can be resumed.
+/// Hands back the updated `State`, along with the final Worker from every object that ran
+/// and how long each took.
+///
+#[allow(clippy::type_complexity)]
+fn run_in_order(
+  order: Vec<ManagedObject>,
+  bars: Vec<ProgressBar>,
+  mut state: State,
+  opts: RunOptions,
+) -> (
+  State,
+  HashMap<String, Worker>,
+  HashMap<String, time::Duration>,
+) {
+  let (tx, rx) = mpsc::channel();
+  let in_run: HashSet<String> = order.iter().map(|o| o.name.clone()).collect();
+  let mut pending: Vec<(ManagedObject, ProgressBar)> = order
+    .into_iter()
+    .zip(bars)
+    .filter(|(mo, _)| !mo.satisfied)
+    .collect();
+  let mut running: HashMap<String, ManagedObject> = HashMap::new();
+  let mut finished: HashMap<String, Worker> = HashMap::new();
+  let mut started: HashMap<String, time::Instant> = HashMap::new();
+  let mut took: HashMap<String, time::Duration> = HashMap::new();
+  let save = |state: &State| {
+    if let Some(f) = &opts.state_file {
+      if let Err(e) = state.save(f) {
+        warn!(
+          "Couldn't save state to {}. Error: {}",
+          f.to_string_lossy(),
+          e
+        );
+      }
+    }
+  };
+  save(&state);
   loop {
-    if let Ok(_t) = rx.try_recv() {
-      v.insert(_t.name.clone(), _t.clone());
+    let mut still_pending: Vec<(ManagedObject, ProgressBar)> = Vec::new();
+    for (mo, p) in pending.drain(..) {
+      let mut ready = true;
+      let mut broken = false;
+      // only dependencies in this run count - anything else was taken care of already
+      for d in mo.dependencies.iter().filter(|d| in_run.contains(*d)) {
+        match state.last_run.get(d) {
+          Some(o) if o.is_done() => {}
+          Some(Outcome::Failed) | Some(Outcome::Skipped) => broken = true,
+          _ => ready = false,
+        }
+      }
+      // `after` just means "not before" - how it went doesn't matter
+      for d in mo.after.iter().filter(|d| in_run.contains(*d)) {
+        if matches!(state.last_run.get(d), None | Some(Outcome::NotStarted)) {
+          ready = false;
+        }
+      }
+      if broken {
+        warn!(
+          "Skipping {}, something it depends on didn't complete.",
+          mo.name
+        );
+        p.abandon_with_message(format!(
+          "{} {}",
+          style("-").yellow(),
+          style("skipped, a dependency failed").dim()
+        ));
+        state.last_run.insert(mo.name.clone(), Outcome::Skipped);
+        save(&state);
+      } else if ready {
+        started.insert(mo.name.clone(), time::Instant::now());
+        if mo.is_task() {
+          send_tasks_off_to_college(&mo, &tx, p, &opts).expect("ohtehnoes");
+        } else {
+          put_file_in_place(&mo, &tx, p, &opts);
+        }
+        running.insert(mo.name.clone(), mo);
+      } else {
+        still_pending.push((mo, p));
+      }
     }
-    std::thread::sleep(time::Duration::from_millis(10));
-    if !all_workers_done(&v) {
-      continue;
+    pending = still_pending;
+    if running.is_empty() {
+      // nothing left to wait on, so nothing left that can become ready
+      break;
+    }
+    if let Ok(w) = rx.recv_timeout(time::Duration::from_millis(100)) {
+      if !w.completed {
+        continue;
+      }
+      if let Some(mo) = running.remove(&w.name) {
+        if let Some(t) = started.get(&mo.name) {
+          took.insert(mo.name.clone(), t.elapsed());
+        }
+        if w.status == Some(0) {
+          if mo.is_task() {
+            state.record_success(&mo);
+          }
+          state.last_run.insert(mo.name, Outcome::Succeeded);
+        } else {
+          state.record_failure(&mo);
+          state.last_run.insert(mo.name, Outcome::Failed);
+        }
+        save(&state);
+        finished.insert(w.name.clone(), w);
+      }
     }
-    break;
   }
-  mp.join().unwrap();
-  Ok(())
+  // only possible if the graph handed to us was broken - but don't leave bars spinning
+  for (mo, p) in pending {
+    warn!("Never got around to {}.", mo.name);
+    p.abandon();
+  }
+  (state, finished, took)
+}
+
+fn object_prefix(mo: &ManagedObject) -> String {
+  let kind = if mo.is_task() { "task" } else { "file" };
+  pad_str(
+    format!("{} {}", kind, mo.name).as_str(),
+    30,
+    Alignment::Left,
+    None,
+  )
+  .into_owned()
 }
 
 ///
-/// Iterate through all the workers passed in. If any isn't marked as complete, `return false;`.
-/// Let's take a reference, because we're only reading, and don't need ownership.
+/// A task we didn't need to run still gets its line, so it doesn't look like we forgot about it.
 ///
-fn all_workers_done(workers: &HashMap<String, config::Worker>) -> bool {
-  for w in workers.values() {
-    if !w.completed {
-      return false;
-    }
+fn finish_unchanged(p: &ProgressBar, mo: &ManagedObject) {
+  p.set_style(
+    ProgressStyle::default_spinner()
+      .template("[{elapsed:4}] {prefix:.bold.dim} {spinner} {wide_msg}"),
+  );
+  p.set_prefix(object_prefix(mo));
+  p.finish_with_message(format!(
+    "{} {}",
+    style("✓").green(),
+    style("unchanged").dim()
+  ));
+}
+
+///
+/// Tasks waiting on their dependencies.
+///
+fn wait_in_line(p: &ProgressBar, mo: &ManagedObject) {
+  p.set_style(
+    ProgressStyle::default_spinner()
+      .template("[{elapsed:4}] {prefix:.bold.dim} {spinner} {wide_msg}"),
+  );
+  p.set_prefix(object_prefix(mo));
+  p.set_message(style("waiting").dim().to_string());
+}
+
+#[cfg(test)]
+mod hm_test {
+  use super::*;
+  use config::Config;
+
+  #[test]
+  fn layers_in_declaration_order() {
+    let a: Config = toml::from_str(
+      r#"
+      [[obj]]
+      task = 'zt'
+      solution = 'true'
+      dependencies = ['slurp', 'grim']
+      [[obj]]
+      task = 'grim'
+      solution = 'true'
+      [[obj]]
+      task = 'slurp'
+      solution = 'true'
+      [[obj]]
+      task = 'fonts'
+      solution = 'true'
+    "#,
+    )
+    .unwrap();
+    let batches = get_task_batches(Config::as_managed_objects(a), HashSet::new()).unwrap();
+    let names: Vec<Vec<&str>> = batches
+      .iter()
+      .map(|b| b.iter().map(|m| m.name.as_str()).collect())
+      .collect();
+    assert_eq!(vec![vec!["grim", "slurp", "fonts"], vec!["zt"]], names);
+  }
+
+  #[test]
+  fn after_is_only_ordering() {
+    let a: Config = toml::from_str(
+      r#"
+      [[obj]]
+      task = 'flatpaks'
+      solution = 'true'
+      after = ['dnf_packages', 'nowhere']
+      [[obj]]
+      task = 'dnf_packages'
+      solution = 'true'
+      os = 'windows'
+      [[obj]]
+      task = 'apt_packages'
+      solution = 'true'
+      [[obj]]
+      task = 'fonts'
+      solution = 'true'
+      after = 'apt_packages'
+    "#,
+    )
+    .unwrap();
+    let layers = |targets: &[&str]| -> Vec<Vec<String>> {
+      get_task_batches(
+        Config::as_managed_objects(a.clone()),
+        targets.iter().map(|t| t.to_string()).collect(),
+      )
+      .unwrap()
+      .iter()
+      .map(|b| b.iter().map(|m| m.name.clone()).collect())
+      .collect()
+    };
+    // dnf_packages isn't for here and nowhere doesn't exist, but that's fine
+    assert_eq!(
+      vec![vec!["flatpaks", "apt_packages"], vec!["fonts"]],
+      layers(&[])
+    );
+    // and `after` doesn't drag anything in
+    assert_eq!(vec![vec!["fonts"]], layers(&["fonts"]));
+  }
+
+  #[test]
+  fn files_and_tasks_depend_on_each_other() {
+    let dir = std::env::temp_dir().join(format!("hm-lib-files-{}", std::process::id()));
+    create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("cfg"), "x").unwrap();
+    let a: Config = toml::from_str(&format!(
+      r#"
+      [[obj]]
+      file = 'cfg'
+      source = '{d}/cfg'
+      destination = '{d}/linked/cfg'
+      method = 'symlink'
+      [[obj]]
+      task = 'build'
+      solution = 'test -L {d}/linked/cfg'
+      dependencies = ['cfg']
+      [[obj]]
+      task = 'solo'
+      solution = 'true'
+    "#,
+      d = dir.display()
+    ))
+    .unwrap();
+    let mos = Config::as_managed_objects(a);
+    let run = |targets: &[&str]| -> HashMap<String, Outcome> {
+      let opts = RunOptions {
+        state_file: Some(dir.join("state.toml")),
+        quiet: true,
+        ..Default::default()
+      };
+      run_tasks(
+        mos.clone(),
+        targets.iter().map(|t| t.to_string()).collect(),
+        opts,
+      )
+      .unwrap()
+      .objects
+      .into_iter()
+      .map(|o| (o.name, o.outcome))
+      .collect()
+    };
+    let first = run(&[]);
+    assert!(first.values().all(|o| *o == Outcome::Succeeded));
+    // the file goes back in place, but that's no reason to build again
+    let second = run(&[]);
+    assert_eq!(Outcome::Succeeded, second["cfg"]);
+    assert_eq!(Outcome::Unchanged, second["build"]);
+    assert_eq!(Outcome::Unchanged, second["solo"]);
+    // -t brings the file build needs along
+    let only = run(&["build"]);
+    let mut names: Vec<&String> = only.keys().collect();
+    names.sort();
+    assert_eq!(vec!["build", "cfg"], names);
+    remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn capture_keeps_the_tail() {
+    let f = std::env::temp_dir().join(format!("hm-capture-{}.log", std::process::id()));
+    let mut c = Capture {
+      log: Some(File::create(&f).unwrap()),
+      tail: VecDeque::new(),
+      tail_lines: 2,
+    };
+    c.line("stdout", "configuring");
+    c.line("stderr", "warning: deprecated");
+    c.line("stdout", "building");
+    // only the last two, whichever stream they came from
+    assert_eq!(vec!["warning: deprecated", "building"], Vec::from(c.tail));
+    // but the log gets everything, in the order it happened
+    let logged: Vec<String> = std::fs::read_to_string(&f)
+      .unwrap()
+      .lines()
+      .map(|l| l.split_once(' ').unwrap().1.to_string())
+      .collect();
+    assert_eq!(
+      vec![
+        "[stdout] configuring",
+        "[stderr] warning: deprecated",
+        "[stdout] building"
+      ],
+      logged
+    );
+    std::fs::remove_file(&f).unwrap();
+    let mut quiet = Capture {
+      log: None,
+      tail: VecDeque::new(),
+      tail_lines: 0,
+    };
+    quiet.line("stdout", "nobody's listening");
+    assert!(quiet.tail.is_empty());
+  }
+
+  #[test]
+  fn task_logs_sit_next_to_the_run() {
+    let run = Path::new("./logs/hm-task-2023-01-01 10:00:00.log");
+    assert_eq!(
+      Path::new("./logs/hm-task-2023-01-01 10:00:00.zt.log"),
+      task_log_path(run, "zt")
+    );
+    // a name can't take us out of the logs directory
+    assert_eq!(
+      Path::new("./logs/hm-task-2023-01-01 10:00:00.dotfiles_zt_slop.log"),
+      task_log_path(run, "dotfiles/zt\\slop")
+    );
   }
-  true
 }
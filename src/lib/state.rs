@@ -0,0 +1,381 @@
+//! Remember which tasks we've already completed successfully, so a daily `hm`
+//! doesn't rebuild the world every time.
+//!
+//! For every task that finishes successfully we write down a hash of its `solution`
+//! and a hash of the contents of its `source` directory into
+//! `$XDG_STATE_HOME/homemaker/state.toml` (`~/.local/state/homemaker/state.toml` if unset).
+//! Next time around, if neither hash has changed, the task is marked `satisfied`
+//! and skipped.
+//!
+//! We also keep the `Outcome` of every task from the most recent run, so
+//! `hm --resume` can pick up where a failed run left off.
+//!
+//! ``` text
+//! [tasks.slop]
+//! solution_hash = "5d1c0e3ba8f6a1c2"
+//! source_hash = "c0ffee00deadbeef"
+//! completed = "2022-10-08T09:12:47-07:00"
+//!
+//! [last_run]
+//! slop = "succeeded"
+//! maim = "failed"
+//! zt = "skipped"
+//! ```
+extern crate serde;
+extern crate toml;
+
+use chrono::prelude::*;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::BTreeMap,
+  fs, io,
+  path::{Path, PathBuf},
+};
+
+use crate::config::{ManagedObject, Shell};
+
+///
+/// What we knew about a task the last time it completed successfully.
+///
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct TaskRecord {
+  pub solution_hash: String,
+  pub source_hash: String,
+  pub completed: String,
+}
+
+///
+/// How a task fared in a run.
+/// * Succeeded: ran, and exited happily.
+/// * Unchanged: didn't need to run; it succeeded before and nothing's changed.
+/// * Failed: ran, and didn't exit happily.
+/// * Skipped: never ran, because something it depends on failed (or was skipped itself).
+/// * NotStarted: never ran, because the run ended before we got to it.
+///
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+  Succeeded,
+  Unchanged,
+  Failed,
+  Skipped,
+  NotStarted,
+}
+
+impl Outcome {
+  /// Whether things depending on this task are good to go.
+  pub fn is_done(&self) -> bool {
+    matches!(self, Outcome::Succeeded | Outcome::Unchanged)
+  }
+}
+
+///
+/// Everything we remember between runs, keyed by task name.
+///
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct State {
+  #[serde(default)]
+  pub tasks: BTreeMap<String, TaskRecord>,
+  #[serde(default)]
+  pub last_run: BTreeMap<String, Outcome>,
+}
+
+impl State {
+  /// Read our state file. A missing or unreadable file just means we start
+  /// from scratch - the worst that happens is we run everything once more.
+  pub fn load(file: &Path) -> State {
+    match fs::read_to_string(file) {
+      Ok(contents) => toml::from_str(&contents).unwrap_or_else(|e| {
+        warn!(
+          "Couldn't parse state file {}, starting fresh. Error: {}",
+          file.to_string_lossy(),
+          e
+        );
+        State::default()
+      }),
+      Err(_e) => State::default(),
+    }
+  }
+
+  /// Write our state file out, creating its directory if need be.
+  pub fn save(&self, file: &Path) -> io::Result<()> {
+    if let Some(p) = file.parent() {
+      fs::create_dir_all(p)?;
+    }
+    let contents = toml::to_string(self).map_err(io::Error::other)?;
+    fs::write(file, contents)
+  }
+
+  /// True if this task completed successfully before, and neither its `solution`
+  /// nor its `source` have changed since.
+  pub fn is_unchanged(&self, mo: &ManagedObject) -> bool {
+    match self.tasks.get(&mo.name) {
+      Some(r) => r.solution_hash == solution_hash(mo) && r.source_hash == source_hash(mo),
+      None => false,
+    }
+  }
+
+  /// Note that this task just completed successfully.
+  /// We hash `source` *after* the task has run, so any build artifacts
+  /// it leaves behind don't count as a change next time.
+  pub fn record_success(&mut self, mo: &ManagedObject) {
+    self.tasks.insert(
+      mo.name.clone(),
+      TaskRecord {
+        solution_hash: solution_hash(mo),
+        source_hash: source_hash(mo),
+        completed: Local::now().to_rfc3339(),
+      },
+    );
+  }
+
+  /// Forget that this task ever succeeded - whatever made it fail this time
+  /// should get another look next time, even if its inputs haven't changed.
+  pub fn record_failure(&mut self, mo: &ManagedObject) {
+    self.tasks.remove(&mo.name);
+  }
+
+  /// True if this task was already taken care of in the last run.
+  pub fn done_last_run(&self, name: &str) -> bool {
+    self
+      .last_run
+      .get(name)
+      .map(|o| o.is_done())
+      .unwrap_or(false)
+  }
+}
+
+/// Where we keep our state: `$XDG_STATE_HOME/homemaker/state.toml`,
+/// falling back to `~/.local/state/homemaker/state.toml`.
+pub fn state_file() -> Result<PathBuf, &'static str> {
+  match std::env::var_os("XDG_STATE_HOME") {
+    Some(p) if !p.is_empty() => Ok(PathBuf::from(p).join("homemaker").join("state.toml")),
+    _ => match dirs_next::home_dir() {
+      Some(h) => Ok(
+        h.join(".local")
+          .join("state")
+          .join("homemaker")
+          .join("state.toml"),
+      ),
+      None => Err("Couldn't get state directory from $XDG or $HOME"),
+    },
+  }
+}
+
+///
+/// 64-bit FNV-1a. These hashes get written down and compared on the next run,
+/// so they have to come out the same whichever Rust we were built with -
+/// `DefaultHasher` makes no such promise.
+///
+struct Fnv(u64);
+
+impl Fnv {
+  fn new() -> Fnv {
+    Fnv(0xcbf2_9ce4_8422_2325)
+  }
+
+  fn write(&mut self, bytes: &[u8]) {
+    for b in bytes {
+      self.0 ^= u64::from(*b);
+      self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+  }
+
+  /// `bytes` with its length in front, so `ab`, `c` and `a`, `bc` don't come out the same.
+  fn field(&mut self, bytes: &[u8]) {
+    self.write(&(bytes.len() as u64).to_le_bytes());
+    self.write(bytes);
+  }
+
+  fn finish(&self) -> String {
+    format!("{:016x}", self.0)
+  }
+}
+
+/// Hash of the `solution` text (or the contents of the `script` file), along with
+/// the `shell`, `cwd` and `env` it runs with.
+pub fn solution_hash(mo: &ManagedObject) -> String {
+  let mut h = Fnv::new();
+  h.field(mo.solution.as_bytes());
+  if !mo.script.is_empty() {
+    h.field(mo.script.as_bytes());
+    // the shell runs it from `cwd`, so that's where a relative path points
+    let mut script = PathBuf::from(shellexpand::tilde(&mo.script).to_string());
+    if script.is_relative() && !mo.cwd.is_empty() {
+      script = PathBuf::from(shellexpand::tilde(&mo.cwd).to_string()).join(script);
+    }
+    if let Ok(c) = fs::read(script) {
+      h.field(&c);
+    }
+  }
+  match &mo.shell {
+    Shell::Named(n) => {
+      h.write(b"n");
+      h.field(n.as_bytes());
+    }
+    Shell::Argv(argv) => {
+      h.write(b"a");
+      h.write(&(argv.len() as u64).to_le_bytes());
+      argv.iter().for_each(|a| h.field(a.as_bytes()));
+    }
+  }
+  h.field(mo.cwd.as_bytes());
+  // HashMap iteration order changes run to run
+  let env: BTreeMap<&String, &String> = mo.env.iter().collect();
+  h.write(&(env.len() as u64).to_le_bytes());
+  for (k, v) in env {
+    h.field(k.as_bytes());
+    h.field(v.as_bytes());
+  }
+  h.finish()
+}
+
+/// Hash of everything under `source` (or of the file itself, if it's a file).
+/// `.git` directories are left out - `git pull` touches them even when there's
+/// nothing new to pull. No `source` (or one that doesn't exist) hashes to an empty string.
+pub fn source_hash(mo: &ManagedObject) -> String {
+  if mo.source.is_empty() {
+    return String::new();
+  }
+  let root = PathBuf::from(shellexpand::tilde(&mo.source).to_string());
+  if !root.exists() {
+    return String::new();
+  }
+  let mut h = Fnv::new();
+  hash_path(&root, &root, &mut h);
+  h.finish()
+}
+
+fn hash_path(root: &Path, p: &Path, h: &mut Fnv) {
+  h.field(
+    p.strip_prefix(root)
+      .unwrap_or(p)
+      .to_string_lossy()
+      .as_bytes(),
+  );
+  let md = match fs::symlink_metadata(p) {
+    Ok(m) => m,
+    Err(_e) => return,
+  };
+  if md.file_type().is_symlink() {
+    if let Ok(t) = fs::read_link(p) {
+      h.write(b"l");
+      h.field(t.to_string_lossy().as_bytes());
+    }
+  } else if md.is_dir() {
+    h.write(b"d");
+    let mut entries: Vec<PathBuf> = match fs::read_dir(p) {
+      Ok(rd) => rd.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+      Err(_e) => return,
+    };
+    // read_dir order isn't guaranteed, and we need the same answer every time
+    entries.sort();
+    for e in entries {
+      if e.file_name().map(|n| n == ".git").unwrap_or(false) {
+        continue;
+      }
+      hash_path(root, &e, h);
+    }
+  } else if let Ok(contents) = fs::read(p) {
+    h.write(b"f");
+    h.field(&contents);
+  }
+}
+
+#[cfg(test)]
+mod state_test {
+  use super::*;
+
+  fn slop(source: &str) -> ManagedObject {
+    ManagedObject {
+      name: "slop".to_string(),
+      source: source.to_string(),
+      solution: "make && sudo make install".to_string(),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn unchanged_after_success() {
+    let mut s = State::default();
+    let mo = slop("./benches");
+    assert!(!s.is_unchanged(&mo));
+    s.record_success(&mo);
+    assert!(s.is_unchanged(&mo));
+  }
+
+  #[test]
+  fn changed_solution_reruns() {
+    let mut s = State::default();
+    let mut mo = slop("./benches");
+    s.record_success(&mo);
+    mo.solution = "make clean && make && sudo make install".to_string();
+    assert!(!s.is_unchanged(&mo));
+  }
+
+  #[test]
+  fn changed_source_reruns() {
+    let dir = std::env::temp_dir().join(format!("hm-state-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("Makefile"), "all:\n").unwrap();
+    let mo = slop(dir.to_str().unwrap());
+    let mut s = State::default();
+    s.record_success(&mo);
+    assert!(s.is_unchanged(&mo));
+    fs::write(dir.join("Makefile"), "all: slop\n").unwrap();
+    assert!(!s.is_unchanged(&mo));
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn hashes_dont_move() {
+    // known FNV-1a values; if these change, everyone's state is thrown away
+    let mut h = Fnv::new();
+    assert_eq!("cbf29ce484222325", h.finish());
+    h.write(b"a");
+    assert_eq!("af63dc4c8601ec8c", h.finish());
+    assert_eq!("ec39dc4f611df7e4", solution_hash(&slop("")));
+  }
+
+  #[test]
+  fn changed_script_in_cwd_reruns() {
+    let dir = std::env::temp_dir().join(format!("hm-state-script-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("s.sh"), "make\n").unwrap();
+    let mo = ManagedObject {
+      name: "slop".to_string(),
+      script: "s.sh".to_string(),
+      cwd: dir.to_str().unwrap().to_string(),
+      ..Default::default()
+    };
+    let mut s = State::default();
+    s.record_success(&mo);
+    assert!(s.is_unchanged(&mo));
+    fs::write(dir.join("s.sh"), "make && sudo make install\n").unwrap();
+    assert!(!s.is_unchanged(&mo));
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn failure_forgets_success() {
+    let mut s = State::default();
+    let mo = slop("./benches");
+    s.record_success(&mo);
+    s.record_failure(&mo);
+    assert!(!s.is_unchanged(&mo));
+  }
+
+  #[test]
+  fn round_trip() {
+    let mut s = State::default();
+    s.record_success(&slop(""));
+    s.last_run.insert("slop".to_string(), Outcome::Failed);
+    let f = std::env::temp_dir()
+      .join(format!("hm-state-rt-{}", std::process::id()))
+      .join("state.toml");
+    s.save(&f).unwrap();
+    assert_eq!(s, State::load(&f));
+    fs::remove_dir_all(f.parent().unwrap()).unwrap();
+  }
+}